@@ -1,18 +1,113 @@
 use std::fmt::{format, Debug, Formatter, Write};
-use std::ops::RangeInclusive;
 use std::path::PathBuf;
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc, Mutex};
 use egui::{Ui, Visuals};
 use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::Instant;
 use egui::Shape::Path;
-use crate::bindings::{WaveformEnum, WaveformEnum_SAW, WaveformEnum_SQR, WaveformEnum_SIN, Patch, ParameterType, ParameterValue, ParameterType_Attack, Section, Section_Filter, ParameterType_Decay, ParameterType_Sustain, ParameterType_Release, ParameterType_Cutoff, ParameterType_Resonance, ParameterType_Emphasis, FilterModeEnum, FilterModeEnum_HP, FilterModeEnum_LP, ParameterType_Mode, Section_Amp, ParameterType_Gain, Section_Osc1, Section_Osc2, Section_Osc3, Section_Global, ParameterType_Waveform, ParameterType_Coarse, ParameterType_Fine};
-use crate::server::run_server;
+use crate::bindings::{WaveformEnum, Patch, ParameterType, ParameterType_Attack, Section, Section_Filter, ParameterType_Decay, ParameterType_Sustain, ParameterType_Release, ParameterType_Cutoff, ParameterType_Resonance, ParameterType_Emphasis, FilterModeEnum, ParameterType_Mode, Section_Amp, ParameterType_Gain, Section_Osc1, Section_Osc2, Section_Osc3, Section_Global, Section_N_SECTIONS, ParameterType_Frequency, ParameterType_Mix, ParameterType_Waveform, ParameterType_Coarse, ParameterType_Fine};
+use crate::bank::{draw_bank, handle_bank, slot_combo, BankPatch, BankWindow, PatchBank};
+use crate::history::{draw_history, handle_history_keys, History};
+use crate::library::{draw_library, Library};
+use crate::morph::{draw_morph, Morph};
+use crate::params;
+use crate::randomize::{draw_randomizer, Randomizer};
+use crate::patch::{diff_patches, handle_patch_files, key_value, patch_from_text, patch_to_text, PatchFile, PatchFiles, PatchInfo};
+use crate::synth::SynthCommand;
+use crate::server::{run_server, Connection, DEFAULT_ADDRESS};
+use crate::arpeggiator::{draw_arpeggiator, run_arpeggiator, ArpState};
+use crate::clock::{run_clock_output, Clock};
+use crate::midi_file::{draw_midi_file_player, run_player, MidiFilePlayer, PlayerState};
+use crate::recorder::{draw_recorder, Recorder, RecorderWindow};
+use crate::keyboard::{draw_keyboard, handle_computer_keyboard, KeyboardState};
+use crate::sequencer::{draw_sequencer, patterns_from_string, patterns_to_string, run_sequencer, SequencerState};
+use crate::midi::{draw_mapping_editor, draw_midi_menu, handle_midi_events, default_mappings, learn_menu, mappings_from_string, mappings_to_string, MidiInputState, MidiOutputState};
+
+const MIDI_MAPPINGS_KEY: &str = "midi_mappings";
+const SEQUENCER_PATTERNS_KEY: &str = "sequencer_patterns";
+const PATCH_KEY: &str = "patch";
+const GLOBALS_KEY: &str = "globals";
+const ENDPOINT_KEY: &str = "endpoint";
+const BANK_PATH_KEY: &str = "bank_path";
+const BANK_SLOT_KEY: &str = "bank_slot";
+const LIBRARY_DIR_KEY: &str = "library_dir";
+const MIDI_INPUT_KEY: &str = "midi_input";
+const MIDI_OUTPUT_KEY: &str = "midi_output";
+const THEME_KEY: &str = "theme";
+const OPEN_WINDOWS_KEY: &str = "open_windows";
+
+#[derive(Clone, Copy, PartialEq, Default)]
+enum Theme {
+    #[default]
+    Dark,
+    Light,
+}
+
+impl Theme {
+    fn visuals(self) -> Visuals {
+        match self {
+            Theme::Dark => Visuals::dark(),
+            Theme::Light => Visuals::light(),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Theme::Dark => "dark",
+            Theme::Light => "light",
+        }
+    }
+}
+
+/// Writes the global settings, which patch files leave out, as `Name = value` lines.
+fn globals_to_string(patch: &PatchUI) -> String {
+    params::PARAMS.iter()
+        .filter(|desc| desc.section == Section_Global)
+        .filter_map(|desc| Some(format!("{} = {}\n", desc.name, desc.write_value(patch.get(desc.section, desc.parameter)?))))
+        .collect()
+}
+
+fn globals_from_string(patch: &mut PatchUI, s: &str) -> Result<(), String> {
+    for line in s.lines().filter(|line| !line.trim().is_empty()) {
+        let (key, value) = key_value(line)?;
+        let desc = params::PARAMS.iter()
+            .find(|desc| desc.section == Section_Global && desc.name == key)
+            .ok_or_else(|| format!("Unknown global setting \"{}\"", key))?;
+        patch.set(desc.section, desc.parameter, desc.read_value(value)?);
+    }
+    Ok(())
+}
 
 pub struct BassSynthUI {
     sender: Sender<Message>,
+    /// Notes played live go through the arpeggiator before reaching the synth.
+    note_sender: Sender<Message>,
     rx: Receiver<Patch>,
     patch: PatchUI,
-
+    keyboard: KeyboardState,
+    midi_input: MidiInputState,
+    midi_output: MidiOutputState,
+    sequencer: Arc<Mutex<SequencerState>>,
+    show_sequencer: bool,
+    arpeggiator: Arc<Mutex<ArpState>>,
+    show_arpeggiator: bool,
+    show_parameters: bool,
+    clock: Arc<Mutex<Clock>>,
+    midi_file_player: MidiFilePlayer,
+    recorder: RecorderWindow,
+    patch_files: PatchFiles,
+    patch_name: String,
+    patch_info: PatchInfo,
+    bank: BankWindow,
+    library: Library,
+    randomizer: Randomizer,
+    morph: Morph,
+    history: History,
+    /// Where the synth is, shared with the server thread, which reconnects when it changes.
+    endpoint: Arc<Mutex<String>>,
+    endpoint_text: String,
+    connection: Arc<Mutex<Connection>>,
+    theme: Theme,
 }
 
 
@@ -21,14 +116,209 @@ impl BassSynthUI {
     pub fn new(cc: &eframe::CreationContext<'_>) -> Self {
         let (tx, rx) = channel();
         let (patch_tx, patch_rx)  = channel();
-        std::thread::spawn(move || { run_server(rx, patch_tx); });
+        let recorder = Arc::new(Mutex::new(Recorder::default()));
+        let shared = recorder.clone();
+        let address = cc.storage.and_then(|s| s.get_string(ENDPOINT_KEY)).unwrap_or_else(|| DEFAULT_ADDRESS.to_string());
+        let endpoint = Arc::new(Mutex::new(address.clone()));
+        let shared_endpoint = endpoint.clone();
+        let connection = Arc::new(Mutex::new(Connection::Waiting));
+        let shared_connection = connection.clone();
+        std::thread::spawn(move || { run_server(rx, patch_tx, shared, shared_endpoint, shared_connection); });
+
+        let clock = Arc::new(Mutex::new(Clock::default()));
+        let mut midi_input = MidiInputState::new(clock.clone());
+        midi_input.refresh_ports();
+        if let Some(mappings) = cc.storage.and_then(|s| s.get_string(MIDI_MAPPINGS_KEY)) {
+            match mappings_from_string(&mappings) {
+                Ok(mappings) => midi_input.mappings = mappings,
+                Err(e) => log::warn!("Ignoring saved MIDI mappings: {}", e),
+            }
+        }
 
-        Self {
+        let mut sequencer = SequencerState::default();
+        if let Some(patterns) = cc.storage.and_then(|s| s.get_string(SEQUENCER_PATTERNS_KEY)) {
+            match patterns_from_string(&patterns) {
+                Ok(patterns) => sequencer.patterns = patterns,
+                Err(e) => log::warn!("Ignoring saved sequencer patterns: {}", e),
+            }
+        }
+        let sequencer = Arc::new(Mutex::new(sequencer));
+        let (shared, shared_clock, sequencer_tx, ctx) = (sequencer.clone(), clock.clone(), tx.clone(), cc.egui_ctx.clone());
+        std::thread::spawn(move || { run_sequencer(shared, shared_clock, sequencer_tx, ctx); });
+
+        let (note_tx, note_rx) = channel();
+        let arpeggiator = Arc::new(Mutex::new(ArpState::default()));
+        let (shared, shared_clock, arp_tx) = (arpeggiator.clone(), clock.clone(), tx.clone());
+        std::thread::spawn(move || { run_arpeggiator(note_rx, shared, shared_clock, arp_tx); });
+
+        let player = Arc::new(Mutex::new(PlayerState::default()));
+        let (shared, shared_clock, player_tx, ctx) = (player.clone(), clock.clone(), tx.clone(), cc.egui_ctx.clone());
+        std::thread::spawn(move || { run_player(shared, shared_clock, player_tx, ctx); });
+
+        let mut midi_output = MidiOutputState::default();
+        midi_output.refresh_ports();
+        let (shared_clock, output) = (clock.clone(), midi_output.connection.clone());
+        std::thread::spawn(move || { run_clock_output(shared_clock, output); });
+        #[cfg(feature = "link")]
+        {
+            let shared_clock = clock.clone();
+            std::thread::spawn(move || { crate::clock::run_link(shared_clock); });
+        }
+
+        let bank = PatchBank::default();
+
+        let mut app = Self {
             sender: tx,
+            note_sender: note_tx,
             rx: patch_rx,
             patch: PatchUI::default(),
+            keyboard: KeyboardState::default(),
+            midi_input,
+            midi_output,
+            sequencer,
+            show_sequencer: false,
+            arpeggiator,
+            show_arpeggiator: false,
+            show_parameters: false,
+            clock,
+            midi_file_player: MidiFilePlayer::new(player),
+            recorder: RecorderWindow::new(recorder),
+            patch_files: PatchFiles::default(),
+            patch_name: bank.patches[0].name.clone(),
+            patch_info: PatchInfo::default(),
+            bank: BankWindow::new(bank),
+            library: Library::default(),
+            randomizer: Randomizer::default(),
+            morph: Morph::default(),
+            history: History::new(&PatchUI::default()),
+            endpoint,
+            endpoint_text: address,
+            connection,
+            theme: Theme::default(),
+        };
+        if let Some(storage) = cc.storage {
+            app.restore(storage, &cc.egui_ctx);
+        }
+        app
+    }
+
+    /// The windows whose open state is remembered, with the names they are saved under.
+    fn windows(&mut self) -> [(&'static str, &mut bool); 11] {
+        [
+            ("sequencer", &mut self.show_sequencer),
+            ("arpeggiator", &mut self.show_arpeggiator),
+            ("parameters", &mut self.show_parameters),
+            ("midi_file", &mut self.midi_file_player.open),
+            ("recorder", &mut self.recorder.open),
+            ("mappings", &mut self.midi_input.show_mappings),
+            ("bank", &mut self.bank.open),
+            ("library", &mut self.library.open),
+            ("randomizer", &mut self.randomizer.open),
+            ("morph", &mut self.morph.open),
+            ("history", &mut self.history.open),
+        ]
+    }
+
+    /// Picks up where `save` left off. A restored patch is sent to the synth in full, since
+    /// what the synth is playing isn't known yet.
+    fn restore(&mut self, storage: &dyn eframe::Storage, ctx: &egui::Context) {
+        let get = |key| storage.get_string(key).filter(|value| !value.is_empty());
+        if let Some(path) = get(BANK_PATH_KEY) {
+            match PatchBank::load(&PathBuf::from(path)) {
+                Ok(bank) => self.bank.set_bank(bank),
+                Err(e) => log::warn!("Not reopening the bank: {}", e),
+            }
+        }
+        if let Some(slot) = get(BANK_SLOT_KEY).and_then(|slot| slot.parse::<usize>().ok()) {
+            let bank = &mut self.bank.bank;
+            bank.current = slot.min(bank.patches.len() - 1);
+            self.patch_name.clone_from(&bank.patches[bank.current].name);
+        }
+        if let Some(text) = get(PATCH_KEY) {
+            match patch_from_text(&text) {
+                Ok(file) => {
+                    self.patch = file.patch.into();
+                    self.patch_name = file.name;
+                    self.patch_info = file.info;
+                }
+                Err(e) => log::warn!("Ignoring the saved patch: {}", e),
+            }
+            if let Some(globals) = get(GLOBALS_KEY) {
+                if let Err(e) = globals_from_string(&mut self.patch, &globals) {
+                    log::warn!("Ignoring saved global settings: {}", e);
+                }
+            }
+            for msg in self.patch.messages() {
+                self.sender.send(msg).unwrap();
+            }
+            self.history.reset(&self.patch);
+        }
+        if let Some(dir) = get(LIBRARY_DIR_KEY) {
+            self.library.scan(&PathBuf::from(dir));
+        }
+        if let Some(name) = get(MIDI_INPUT_KEY) {
+            self.midi_input.connect_named(&name, &self.note_sender, ctx);
+        }
+        if let Some(name) = get(MIDI_OUTPUT_KEY) {
+            self.midi_output.connect_named(&name);
+        }
+        if get(THEME_KEY).as_deref() == Some(Theme::Light.name()) {
+            self.theme = Theme::Light;
+        }
+        if let Some(open) = get(OPEN_WINDOWS_KEY) {
+            let open: Vec<&str> = open.split(',').collect();
+            for (name, shown) in self.windows() {
+                *shown = open.contains(&name);
+            }
         }
     }
+
+    /// Puts the patch, settings and layout back to how they are on a first launch. The bank and
+    /// MIDI connections are left alone.
+    fn reset_to_defaults(&mut self, ctx: &egui::Context) {
+        self.patch = PatchUI::default();
+        for msg in self.patch.messages() {
+            self.sender.send(msg).unwrap();
+        }
+        self.patch_name = BankPatch::init().name;
+        self.patch_info = PatchInfo::default();
+        self.midi_input.mappings = default_mappings();
+        self.endpoint_text = DEFAULT_ADDRESS.to_string();
+        *self.endpoint.lock().unwrap() = self.endpoint_text.clone();
+        self.theme = Theme::default();
+        for (_, shown) in self.windows() {
+            *shown = false;
+        }
+        ctx.memory_mut(|memory| memory.reset_areas());
+    }
+}
+
+/// Where the synth is, the look, and a way back to the defaults.
+fn draw_settings_menu(endpoint: &Mutex<String>, endpoint_text: &mut String, connection: &Mutex<Connection>, theme: &mut Theme, reset: &mut bool, ui: &mut Ui) {
+    ui.label("Synth endpoint");
+    ui.horizontal(|ui| {
+        ui.text_edit_singleline(endpoint_text);
+        let changed = *endpoint.lock().unwrap() != *endpoint_text;
+        if ui.add_enabled(changed, egui::Button::new("Connect")).clicked() {
+            endpoint.lock().unwrap().clone_from(endpoint_text);
+        }
+    });
+    match &*connection.lock().unwrap() {
+        Connection::Waiting => ui.weak("Waiting for the synth"),
+        Connection::Connected => ui.label("Connected"),
+        Connection::Failed(error) => ui.colored_label(ui.visuals().error_fg_color, error.as_str()),
+    };
+    ui.separator();
+    ui.horizontal(|ui| {
+        ui.label("Theme");
+        ui.selectable_value(theme, Theme::Dark, "Dark");
+        ui.selectable_value(theme, Theme::Light, "Light");
+    });
+    ui.separator();
+    if ui.button("Reset to defaults").on_hover_text("Patch, settings and window layout; the bank is kept").clicked() {
+        *reset = true;
+        ui.close_menu();
+    }
 }
 
 #[derive(Default)]
@@ -58,8 +348,6 @@ impl Default for EnvelopeCfg {
 }
 
 
-const TimeWindow: RangeInclusive<f32> = 20.0..=2000.0;
-const FreqWindow: RangeInclusive<f32> = 20.0..=20000.0;
 #[derive(Default)]
 pub struct FilterCfg {
     pub filter_type: FilterModeEnum,
@@ -70,20 +358,13 @@ pub struct FilterCfg {
 }
 
 type Osc = u8;
+pub type Note = u8;
 #[derive(Debug)]
 pub enum Message {
-    SetWaveform(Osc, WaveformEnum),
-    SetCoarse(Osc, i8),
-    SetFine(Osc, i8),
-    SetOscGain(Osc, i8),
-    SetParameter(Section, ParameterType, ParameterValue)
-}
-
-impl Debug for ParameterValue {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        unsafe {write!(f, "{:?}", self.freq.to_le_bytes())}
-    }
-    
+    Synth(SynthCommand),
+    /// Notes carry when they were played, so recording isn't skewed by them waiting to be sent.
+    NoteOn(Note, u8, Instant),
+    NoteOff(Note, Instant),
 }
 
 #[derive(Default)]
@@ -92,9 +373,27 @@ pub struct AmplConfig {
     pub gain: i8,
 }
 
+/// Settings for the whole synth. They are not part of a `Patch`, so they stay put when
+/// patches change.
+#[derive(Clone, Copy)]
+pub struct GlobalCfg {
+    /// Frequency of A4, in Hz.
+    pub tuning: f32,
+    /// Level of the oscillator mix going into the filter.
+    pub mix: f32,
+}
+
+impl Default for GlobalCfg {
+    fn default() -> Self {
+        GlobalCfg {
+            tuning: params::param(Section_Global, ParameterType_Frequency).default,
+            mix: params::param(Section_Global, ParameterType_Mix).default,
+        }
+    }
+}
 
-#[derive(Default)]
 pub struct PatchUI {
+    pub global: GlobalCfg,
     pub osc_1: OscillatorCfg,
     pub osc_2: OscillatorCfg,
     pub osc_3: OscillatorCfg,
@@ -102,52 +401,252 @@ pub struct PatchUI {
     pub amp: AmplConfig
 }
 
+impl Default for PatchUI {
+    fn default() -> Self {
+        let mut patch = PatchUI {
+            global: GlobalCfg::default(),
+            osc_1: OscillatorCfg::default(),
+            osc_2: OscillatorCfg::default(),
+            osc_3: OscillatorCfg::default(),
+            filter: FilterCfg::default(),
+            amp: AmplConfig::default(),
+        };
+        for desc in &params::PARAMS {
+            patch.set(desc.section, desc.parameter, desc.default);
+        }
+        patch
+    }
+}
+
+impl PatchUI {
+    fn oscillator_mut(&mut self, section: Section) -> Option<&mut OscillatorCfg> {
+        match section {
+            Section_Osc1 => Some(&mut self.osc_1),
+            Section_Osc2 => Some(&mut self.osc_2),
+            Section_Osc3 => Some(&mut self.osc_3),
+            _ => None
+        }
+    }
+
+    /// The field holding a parameter, if the patch has one for it.
+    fn field_mut(&mut self, section: Section, parameter: ParameterType) -> Option<Field<'_>> {
+        if matches!(section, Section_Osc1 | Section_Osc2 | Section_Osc3) {
+            let osc = self.oscillator_mut(section)?;
+            return match parameter {
+                ParameterType_Waveform => Some(Field::U8(&mut osc.waveform)),
+                ParameterType_Coarse => Some(Field::I8(&mut osc.coarse)),
+                ParameterType_Fine => Some(Field::I8(&mut osc.fine)),
+                ParameterType_Gain => Some(Field::I8(&mut osc.gain)),
+                _ => None
+            };
+        }
+        let envelope = match section {
+            Section_Global => return match parameter {
+                ParameterType_Frequency => Some(Field::F32(&mut self.global.tuning)),
+                ParameterType_Mix => Some(Field::F32(&mut self.global.mix)),
+                _ => None
+            },
+            Section_Filter => match parameter {
+                ParameterType_Mode => return Some(Field::U8(&mut self.filter.filter_type)),
+                ParameterType_Cutoff => return Some(Field::F32(&mut self.filter.cutoff)),
+                ParameterType_Resonance => return Some(Field::U8(&mut self.filter.resonance)),
+                ParameterType_Emphasis => return Some(Field::F32(&mut self.filter.emphasis)),
+                _ => &mut self.filter.envelope
+            },
+            Section_Amp => match parameter {
+                ParameterType_Gain => return Some(Field::I8(&mut self.amp.gain)),
+                _ => &mut self.amp.envelope
+            },
+            _ => return None
+        };
+        match parameter {
+            ParameterType_Attack => Some(Field::F32(&mut envelope.attack)),
+            ParameterType_Decay => Some(Field::F32(&mut envelope.decay)),
+            ParameterType_Sustain => Some(Field::F32(&mut envelope.sustain)),
+            ParameterType_Release => Some(Field::F32(&mut envelope.release)),
+            _ => None
+        }
+    }
+
+    pub fn get(&self, section: Section, parameter: ParameterType) -> Option<f32> {
+        let osc = match section {
+            Section_Osc1 => &self.osc_1,
+            Section_Osc2 => &self.osc_2,
+            Section_Osc3 => &self.osc_3,
+            _ => {
+                let envelope = match section {
+                    Section_Filter => &self.filter.envelope,
+                    Section_Amp => &self.amp.envelope,
+                    _ => &EnvelopeCfg::default()
+                };
+                return match (section, parameter) {
+                    (Section_Global, ParameterType_Frequency) => Some(self.global.tuning),
+                    (Section_Global, ParameterType_Mix) => Some(self.global.mix),
+                    (Section_Filter, ParameterType_Mode) => Some(self.filter.filter_type as f32),
+                    (Section_Filter, ParameterType_Cutoff) => Some(self.filter.cutoff),
+                    (Section_Filter, ParameterType_Resonance) => Some(self.filter.resonance as f32),
+                    (Section_Filter, ParameterType_Emphasis) => Some(self.filter.emphasis),
+                    (Section_Amp, ParameterType_Gain) => Some(self.amp.gain as f32),
+                    (Section_Filter | Section_Amp, ParameterType_Attack) => Some(envelope.attack),
+                    (Section_Filter | Section_Amp, ParameterType_Decay) => Some(envelope.decay),
+                    (Section_Filter | Section_Amp, ParameterType_Sustain) => Some(envelope.sustain),
+                    (Section_Filter | Section_Amp, ParameterType_Release) => Some(envelope.release),
+                    _ => None
+                };
+            }
+        };
+        match parameter {
+            ParameterType_Waveform => Some(osc.waveform as f32),
+            ParameterType_Coarse => Some(osc.coarse as f32),
+            ParameterType_Fine => Some(osc.fine as f32),
+            ParameterType_Gain => Some(osc.gain as f32),
+            _ => None
+        }
+    }
+
+    /// The messages that set every parameter, for a synth whose state isn't known.
+    pub fn messages(&self) -> Vec<Message> {
+        params::PARAMS.iter()
+            .filter_map(|desc| Some(Message::Synth(desc.command(self.get(desc.section, desc.parameter)?))))
+            .collect()
+    }
+
+    /// Replaces the patch, keeping the global settings, and returns the messages that bring the
+    /// synth in line with it.
+    pub fn load(&mut self, patch: Patch) -> Vec<Message> {
+        let messages = diff_patches(&Patch::from(&*self), &patch)
+            .into_iter()
+            .filter_map(|msg| SynthCommand::try_from(msg).ok())
+            .map(Message::Synth)
+            .collect();
+        let global = self.global;
+        *self = patch.into();
+        self.global = global;
+        messages
+    }
+
+    /// Sets a parameter, limited to its range, and returns the message that sends the new
+    /// value to the synth.
+    pub fn set(&mut self, section: Section, parameter: ParameterType, value: f32) -> Option<Message> {
+        let desc = params::find(section, parameter)?;
+        let value = desc.clamp(value);
+        match self.field_mut(section, parameter)? {
+            Field::F32(field) => *field = value,
+            Field::I8(field) => *field = value as i8,
+            Field::U8(field) => *field = value as u8,
+        }
+        Some(Message::Synth(desc.command(value)))
+    }
+
+    /// Sets a parameter from a control position in `0.0..=1.0`, such as a MIDI CC, and returns
+    /// the message that sends the new value to the synth.
+    pub fn set_normalised(&mut self, section: Section, parameter: ParameterType, x: f32) -> Option<Message> {
+        let desc = params::find(section, parameter)?;
+        self.set(section, parameter, desc.denormalise(x))
+    }
+}
+
+enum Field<'a> {
+    F32(&'a mut f32),
+    I8(&'a mut i8),
+    U8(&'a mut u8),
+}
+
+/// `Patch` is the synth's own layout and `PatchUI` the one the controls edit. Both conversions
+/// name every field of `Patch`, so the compiler flags either side if the C struct changes, and
+/// converting one way and back again gives the same patch. The global settings are not part of
+/// a patch.
 impl From<Patch> for PatchUI {
     fn from(value: Patch) -> Self {
+        let Patch {
+            Osc1_Waveform, Osc2_Waveform, Osc3_Waveform, Filter_Mode,
+            Osc1_Coarse, Osc1_Fine, Osc1_Gain,
+            Osc2_Coarse, Osc2_Fine, Osc2_Gain,
+            Osc3_Coarse, Osc3_Fine, Osc3_Gain,
+            Filter_Resonance, Amp_Gain, Filter_Cutoff,
+            Filter_Attack, Filter_Decay, Filter_Sustain, Filter_Release, Filter_Emphasis,
+            Amp_Attack, Amp_Decay, Amp_Sustain, Amp_Release,
+        } = value;
         PatchUI{
+            global: GlobalCfg::default(),
             osc_1: OscillatorCfg{
-                waveform: value.Osc1_Waveform,
-                coarse: value.Osc1_Coarse,
-                fine: value.Osc1_Fine,
-                gain: value.Osc1_Gain,
+                waveform: Osc1_Waveform,
+                coarse: Osc1_Coarse,
+                fine: Osc1_Fine,
+                gain: Osc1_Gain,
             },
             osc_2: OscillatorCfg{
-                waveform: value.Osc2_Waveform,
-                coarse: value.Osc2_Coarse,
-                fine: value.Osc2_Fine,
-                gain: value.Osc2_Gain,
+                waveform: Osc2_Waveform,
+                coarse: Osc2_Coarse,
+                fine: Osc2_Fine,
+                gain: Osc2_Gain,
             },
             osc_3: OscillatorCfg{
-                waveform: value.Osc3_Waveform,
-                coarse: value.Osc3_Coarse,
-                fine: value.Osc3_Fine,
-                gain: value.Osc3_Gain,
+                waveform: Osc3_Waveform,
+                coarse: Osc3_Coarse,
+                fine: Osc3_Fine,
+                gain: Osc3_Gain,
             },
             filter: FilterCfg{
-                filter_type: value.Filter_Mode,
+                filter_type: Filter_Mode,
                 envelope:EnvelopeCfg{
-                    attack: value.Filter_Attack,
-                    decay: value.Filter_Decay,
-                    sustain: value.Filter_Sustain,
-                    release: value.Filter_Release
+                    attack: Filter_Attack,
+                    decay: Filter_Decay,
+                    sustain: Filter_Sustain,
+                    release: Filter_Release
                 },
-                cutoff: value.Filter_Cutoff,
-                resonance: value.Filter_Resonance,
-                emphasis: value.Filter_Emphasis
+                cutoff: Filter_Cutoff,
+                resonance: Filter_Resonance,
+                emphasis: Filter_Emphasis
             },
             amp: AmplConfig{
-                gain: value.Amp_Gain,
+                gain: Amp_Gain,
                 envelope:EnvelopeCfg{
-                    attack: value.Amp_Attack,
-                    decay: value.Amp_Decay,
-                    sustain: value.Amp_Sustain,
-                    release: value.Amp_Release
+                    attack: Amp_Attack,
+                    decay: Amp_Decay,
+                    sustain: Amp_Sustain,
+                    release: Amp_Release
                 }
             }
         }
     }
 }
 
+impl From<&PatchUI> for Patch {
+    fn from(value: &PatchUI) -> Self {
+        let PatchUI { global: _, osc_1, osc_2, osc_3, filter, amp } = value;
+        let FilterCfg { filter_type, envelope: filter_envelope, cutoff, resonance, emphasis } = filter;
+        let AmplConfig { envelope: amp_envelope, gain: amp_gain } = amp;
+        Patch {
+            Osc1_Waveform: osc_1.waveform,
+            Osc2_Waveform: osc_2.waveform,
+            Osc3_Waveform: osc_3.waveform,
+            Filter_Mode: *filter_type,
+            Osc1_Coarse: osc_1.coarse,
+            Osc1_Fine: osc_1.fine,
+            Osc1_Gain: osc_1.gain,
+            Osc2_Coarse: osc_2.coarse,
+            Osc2_Fine: osc_2.fine,
+            Osc2_Gain: osc_2.gain,
+            Osc3_Coarse: osc_3.coarse,
+            Osc3_Fine: osc_3.fine,
+            Osc3_Gain: osc_3.gain,
+            Filter_Resonance: *resonance,
+            Amp_Gain: *amp_gain,
+            Filter_Cutoff: *cutoff,
+            Filter_Attack: filter_envelope.attack,
+            Filter_Decay: filter_envelope.decay,
+            Filter_Sustain: filter_envelope.sustain,
+            Filter_Release: filter_envelope.release,
+            Filter_Emphasis: *emphasis,
+            Amp_Attack: amp_envelope.attack,
+            Amp_Decay: amp_envelope.decay,
+            Amp_Sustain: amp_envelope.sustain,
+            Amp_Release: amp_envelope.release,
+        }
+    }
+}
+
 fn draw_oscillator_section(oscillator_cfg: &mut OscillatorCfg, index: Osc, sender: &mpsc::Sender<Message>, ui: &mut Ui) {
     let OscillatorCfg {
         waveform,
@@ -156,78 +655,75 @@ fn draw_oscillator_section(oscillator_cfg: &mut OscillatorCfg, index: Osc, sende
         gain
     } = oscillator_cfg;
     ui.heading(format!("Osc {}", index + 1));
-    let section = match index {
-        0 => {Section_Osc1},
-        1 => {Section_Osc2},
-        2 => {Section_Osc3},
-        _ => {Section_Global}
-    };
-    ui.horizontal(|ui| {
-        ui.selectable_value(waveform, WaveformEnum_SIN, "Sin")
-            .changed()
-            .then(|| { sender.send(
-                Message::SetParameter(section, ParameterType_Waveform, ParameterValue{value_WaveformEnum: WaveformEnum_SIN})
-            ).unwrap(); });
-        ui.selectable_value(waveform, WaveformEnum_SAW, "Saw")
-            .changed()
-            .then(|| { sender.send(Message::SetParameter(section, ParameterType_Waveform, ParameterValue{value_WaveformEnum: WaveformEnum_SAW})
-          ).unwrap(); });
-        ui.selectable_value(waveform, WaveformEnum_SQR, "Sqr")
-            .changed()
-            .then(|| { sender.send(Message::SetParameter(section, ParameterType_Waveform, ParameterValue{value_WaveformEnum: WaveformEnum_SQR})).unwrap(); });
-    });
+    let section = Section_Osc1 + index;
+    ui.horizontal(|ui| add_choices(waveform, section, ParameterType_Waveform, ui, sender));
     ui.end_row();
 
     egui::Grid::new(format!("Osc {}", index + 1))
         .num_columns(2)
         .show(ui, |ui| {
-            ui.label("Coarse");
-            ui.add(egui::DragValue::new(coarse)
-                .speed(1.0)
-                .range(RangeInclusive::new(-24, 24)))
-                .drag_stopped()
-                .then(|| { sender.send(Message::SetParameter(section, ParameterType_Coarse, ParameterValue{value_int8_t: coarse.clone()})) });
+            add_drag_value(coarse, section, ParameterType_Coarse, ui, sender);
             ui.end_row();
 
-
-            ui.label("Fine");
-            ui.add(egui::DragValue::new(fine).speed(1.0).range(RangeInclusive::new(-50, 50)))
-                .drag_stopped()
-                .then(|| { sender.send(Message::SetParameter(section, ParameterType_Fine, ParameterValue{value_int8_t:fine.clone()}))});
+            add_drag_value(fine, section, ParameterType_Fine, ui, sender);
             ui.end_row();
 
-            ui.vertical(|ui| {
-                let slider = egui::Slider::new(gain, i8::MIN..=6)
-                    .vertical()
-                    .custom_formatter(|n, _| {
-                        let n = n as i8;
-                        if n == i8::MIN {
-                            format!("-inf")
-                        } else {
-                            format!("{}", n)
-                        }
-                    });
-                ui.add(slider)
-                    .drag_stopped()
-                    .then(|| { sender.send(Message::SetParameter(section, ParameterType_Gain, ParameterValue{value_int8_t: gain.clone()}))});
-                ui.end_row();
-            });
+            add_slider(gain, section, ParameterType_Gain, ui, sender);
+            ui.end_row();
         });
 }
 
+/// One selectable label per value of a stepped parameter.
+fn add_choices(value: &mut u8, section: Section, parameter: ParameterType, ui: &mut Ui, sender: &Sender<Message>) {
+    let desc = params::param(section, parameter);
+    for &(choice, name) in desc.choices {
+        let response = ui.selectable_value(value, choice, name);
+        learn_menu(&response, section, parameter);
+        if response.changed() {
+            sender.send(Message::Synth(desc.command(choice as f32))).unwrap();
+        }
+    }
+}
+
+fn add_drag_value<T>(value: &mut T, section: Section, parameter: ParameterType, ui: &mut Ui, sender: &Sender<Message>)
+where T: egui::emath::Numeric
+{
+    let desc = params::param(section, parameter);
+    ui.label(desc.label);
+    let response = ui.add(egui::DragValue::new(value)
+        .speed(1.0)
+        .range(T::from_f64(desc.min as f64)..=T::from_f64(desc.max as f64))
+        .custom_formatter(|n, _| desc.format(n as f32))
+        .custom_parser(|text| desc.parse(text).map(|v| v as f64)));
+    learn_menu(&response, section, parameter);
+    response
+        .drag_stopped()
+        .then(|| { sender.send(Message::Synth(desc.command(value.to_f64() as f32))) });
+}
 
-fn add_slider<T>(value: &mut T, range: RangeInclusive<T>, name: &str, ui: &mut Ui, sender: &Sender<Message>, on_changed: fn(T) -> Message)
+/// A vertical slider that moves along the parameter's scale, so a log-scaled cutoff or
+/// envelope time gets as much travel at the low end as at the high end.
+fn add_slider<T>(value: &mut T, section: Section, parameter: ParameterType, ui: &mut Ui, sender: &Sender<Message>)
 where T: egui::emath::Numeric
 {
+    let desc = params::param(section, parameter);
     ui.vertical( |ui| {
-        ui.label(name);
-        let slider = egui::Slider::new(value, range)
-            .vertical();
+        ui.label(desc.label);
+        let mut position = desc.normalise(value.to_f64() as f32);
+        let slider = egui::Slider::new(&mut position, 0.0..=1.0)
+            .vertical()
+            .custom_formatter(|x, _| desc.format(desc.denormalise(x as f32)))
+            .custom_parser(|text| desc.parse(text).map(|v| desc.normalise(v) as f64));
 
-        ui.add(slider)
-            .drag_stopped()
+        let response = ui.add(slider);
+        if response.changed() {
+            *value = T::from_f64(desc.denormalise(position) as f64);
+        }
+        learn_menu(&response, section, parameter);
+        // Typed values only commit once editing ends, so send them as they change.
+        (response.drag_stopped() || (response.changed() && !response.dragged()))
             .then(|| {
-                let msg = on_changed(value.clone());
+                let msg = Message::Synth(desc.command(value.to_f64() as f32));
                 sender.send(msg).unwrap();
             });
     });
@@ -244,42 +740,19 @@ fn draw_filter_section(filter_cfg: &mut FilterCfg, sender: &Sender<Message>, ui:
             .num_columns(2)
             .show(ui, |ui| {
                 ui.heading("Filter");;
-                ui.horizontal(|ui| {
-                    ui.selectable_value(filter_type, FilterModeEnum_HP, "HP")
-                        .changed().then(|| sender.send(
-                        Message::SetParameter(Section_Filter, ParameterType_Mode, ParameterValue{value_FilterModeEnum: FilterModeEnum_HP})
-                    ));
-                    ui.selectable_value(filter_type, FilterModeEnum_LP, "LP")
-                        .changed().then(|| sender.send(
-                        Message::SetParameter(Section_Filter, ParameterType_Mode, ParameterValue{value_FilterModeEnum: FilterModeEnum_LP})
-                    ));
-                })
+                ui.horizontal(|ui| add_choices(filter_type, Section_Filter, ParameterType_Mode, ui, sender))
             }
             );
 
         ui.horizontal(
             |ui| {
-                add_slider(cutoff, FreqWindow, "Freq", ui, sender, |v| {
-                    Message::SetParameter(Section_Filter, ParameterType_Cutoff, ParameterValue { value_float: v })
-                });
-                add_slider(resonance, 0..=u8::MAX, "Reso", ui, sender, |v| {
-                    Message::SetParameter(Section_Filter, ParameterType_Resonance, ParameterValue { value_uint8_t: v })
-                });
-                add_slider(emphasis, 0.0..=1.0, "Emph", ui, sender, |v| {
-                    Message::SetParameter(Section_Filter, ParameterType_Emphasis, ParameterValue { value_float: v })
-                });
-                add_slider(attack, TimeWindow, "A", ui, sender, |v| {
-                    Message::SetParameter(Section_Filter, ParameterType_Attack, ParameterValue { value_float: v })
-                });
-                add_slider(decay, TimeWindow, "D", ui, sender, |v| {
-                    Message::SetParameter(Section_Filter, ParameterType_Decay, ParameterValue { value_float: v })
-                });
-                add_slider(sustain, 0.0..=1.0, "S", ui, sender, |v| {
-                    Message::SetParameter(Section_Filter, ParameterType_Sustain, ParameterValue { value_float: v })
-                });
-                add_slider(release, TimeWindow, "R", ui, sender, |v| {
-                    Message::SetParameter(Section_Filter, ParameterType_Release, ParameterValue { value_float: v })
-                });
+                add_slider(cutoff, Section_Filter, ParameterType_Cutoff, ui, sender);
+                add_slider(resonance, Section_Filter, ParameterType_Resonance, ui, sender);
+                add_slider(emphasis, Section_Filter, ParameterType_Emphasis, ui, sender);
+                add_slider(attack, Section_Filter, ParameterType_Attack, ui, sender);
+                add_slider(decay, Section_Filter, ParameterType_Decay, ui, sender);
+                add_slider(sustain, Section_Filter, ParameterType_Sustain, ui, sender);
+                add_slider(release, Section_Filter, ParameterType_Release, ui, sender);
             }
         );
     });
@@ -296,71 +769,118 @@ fn draw_amp_section(ampl_config: &mut AmplConfig, sender: &Sender<Message>, ui:
 
             ui.horizontal(
                 |ui| {
-                    add_slider(attack, TimeWindow, "A", ui, sender, |v| {
-                        Message::SetParameter(Section_Amp, ParameterType_Attack, ParameterValue { value_float: v })
-                    });
-                    add_slider(decay, TimeWindow, "D", ui, sender, |v| {
-                        Message::SetParameter(Section_Amp, ParameterType_Decay, ParameterValue { value_float: v })
-                    });
-                    add_slider(sustain, 0.0..=1.0, "S", ui, sender, |v| {
-                        Message::SetParameter(Section_Amp, ParameterType_Sustain, ParameterValue { value_float: v })
-                    });
-                    add_slider(release, TimeWindow, "R", ui, sender, |v| {
-                        Message::SetParameter(Section_Amp, ParameterType_Release, ParameterValue { value_float: v })
-                    });
-                    ui.vertical(|ui| {
-                        ui.label("Gain");
-                        let slider = egui::Slider::new(gain, i8::MIN..=6)
-                            .vertical()
-                            .custom_formatter(|n, _| {
-                                let n = n as i8;
-                                if n == i8::MIN {
-                                    format!("-inf")
-                                } else {
-                                    format!("{}", n)
-                                }
-                            });
-                        ui.add(slider)
-                            .drag_stopped()
-                            .then(|| { sender.send(Message::SetParameter(Section_Amp, ParameterType_Gain, ParameterValue { value_int8_t: gain.clone() })) });
-                    }
-                    );
+                    add_slider(attack, Section_Amp, ParameterType_Attack, ui, sender);
+                    add_slider(decay, Section_Amp, ParameterType_Decay, ui, sender);
+                    add_slider(sustain, Section_Amp, ParameterType_Sustain, ui, sender);
+                    add_slider(release, Section_Amp, ParameterType_Release, ui, sender);
+                    add_slider(gain, Section_Amp, ParameterType_Gain, ui, sender);
                 });
         });
     });
 }
 
-struct PatchBank {
-    path: PathBuf,
-    patches: Vec<Patch>,
-    dirty: bool,
+fn draw_global_section(global_cfg: &mut GlobalCfg, sender: &Sender<Message>, ui: &mut Ui) {
+    ui.group(|ui| {
+        ui.vertical(|ui| {
+            ui.heading("Global");
+            let GlobalCfg { tuning, mix } = global_cfg;
+            ui.horizontal(|ui| {
+                add_slider(mix, Section_Global, ParameterType_Mix, ui, sender);
+                add_slider(tuning, Section_Global, ParameterType_Frequency, ui, sender);
+            });
+        });
+    });
 }
 
-fn draw_patch_section(ui: &mut Ui){
-    let number = 0;
-    let file = std::path::Path::new("patch.patch");
+/// Lists every parameter in the registry, for editing values that have no control of their
+/// own or reading them more precisely than the panel allows.
+fn draw_parameter_editor(patch: &mut PatchUI, sender: &Sender<Message>, open: &mut bool, ctx: &egui::Context) {
+    egui::Window::new("Parameters").open(open).vscroll(true).show(ctx, |ui| {
+        for section in Section_Global..Section_N_SECTIONS {
+            egui::CollapsingHeader::new(params::section_name(section))
+                .default_open(section == Section_Global)
+                .show(ui, |ui| {
+                    egui::Grid::new(("Parameters", section)).num_columns(3).show(ui, |ui| {
+                        for desc in params::PARAMS.iter().filter(|p| p.section == section) {
+                            let Some(value) = patch.get(desc.section, desc.parameter) else {
+                                continue;
+                            };
+                            ui.label(desc.name);
+                            let mut position = desc.normalise(value);
+                            let response = ui.add(egui::Slider::new(&mut position, 0.0..=1.0)
+                                .custom_formatter(|x, _| desc.format(desc.denormalise(x as f32)))
+                                .custom_parser(|text| desc.parse(text).map(|v| desc.normalise(v) as f64)));
+                            learn_menu(&response, desc.section, desc.parameter);
+                            let mut changed = response.changed().then(|| desc.denormalise(position));
+                            let mut commit = response.drag_stopped() || (response.changed() && !response.dragged());
+                            if ui.small_button("Reset").on_hover_text(desc.format(desc.default)).clicked() {
+                                changed = Some(desc.default);
+                                commit = true;
+                            }
+                            // Values are only set when edited here, so showing one the synth sent
+                            // doesn't clamp it.
+                            if let Some(value) = changed.or(commit.then_some(value)) {
+                                if let Some(msg) = patch.set(desc.section, desc.parameter, value).filter(|_| commit) {
+                                    sender.send(msg).unwrap();
+                                }
+                            }
+                            ui.end_row();
+                        }
+                    });
+                });
+        }
+    });
+}
 
+fn draw_patch_section(files: &mut PatchFiles, bank: &mut BankWindow, patch: &mut PatchUI, name: &mut String, sender: &Sender<Message>, ui: &mut Ui){
+    let edited = bank.edited;
+    let comparing = bank.comparing();
     ui.group(
         |ui|{
             ui.vertical(
               |ui|{
-                  ui.heading("Patch");
+                  ui.heading(if edited || comparing { "Patch*" } else { "Patch" });
                   ui.end_row();
+                  ui.add(egui::TextEdit::singleline(name).hint_text("Name").desired_width(60.0));
                   if ui.button("Open").clicked() {
-
+                      files.open();
                   };
                   ui.end_row();
-                  if ui.button("Save").clicked() {
-
+                  let mut save = ui.button("Save");
+                  if let Some(status) = &files.status {
+                      save = save.on_hover_text(status.as_str());
+                  }
+                  if save.clicked() {
+                      files.save();
                   }
                   ui.end_row();
                   ui.horizontal(
                       |ui|{
-                          if ui.button("<").clicked(){};
-                          ui.label(format!("{}", number));
-                          if ui.button(">").clicked(){};
+                          let current = bank.bank.current;
+                          if ui.add_enabled(current > 0, egui::Button::new("<")).clicked() {
+                              bank.request_select(current - 1);
+                          }
+                          slot_combo(bank, "Patch slot", ui);
+                          if ui.add_enabled(current + 1 < bank.bank.patches.len(), egui::Button::new(">")).clicked() {
+                              bank.request_select(current + 1);
+                          }
+                      }
+                  );
+                  ui.horizontal(
+                      |ui|{
+                          let compare = ui.add_enabled(edited || comparing, egui::SelectableLabel::new(comparing, "Compare"))
+                              .on_hover_text("Switch between the edit and the stored patch");
+                          if compare.clicked() {
+                              bank.toggle_compare(patch, name, sender);
+                          }
+                          if ui.add_enabled(edited || comparing, egui::Button::new("Commit")).on_hover_text("Store the edit in this slot").clicked() {
+                              bank.commit(patch, name, sender);
+                          }
+                          if ui.add_enabled(edited || comparing, egui::Button::new("Discard")).on_hover_text("Go back to the stored patch").clicked() {
+                              bank.discard(patch, name, sender);
+                          }
                       }
-                  )
+                  );
 
               }
             );
@@ -370,22 +890,101 @@ fn draw_patch_section(ui: &mut Ui){
 
 impl eframe::App for BassSynthUI {
     /// Called by the frame work to save state before shutdown.
-    fn save(&mut self, storage: &mut dyn eframe::Storage) {}
+    fn save(&mut self, storage: &mut dyn eframe::Storage) {
+        storage.set_string(MIDI_MAPPINGS_KEY, mappings_to_string(&self.midi_input.mappings));
+        let patterns = patterns_to_string(&self.sequencer.lock().unwrap().patterns);
+        storage.set_string(SEQUENCER_PATTERNS_KEY, patterns);
+        let file = PatchFile { name: self.patch_name.clone(), info: self.patch_info.clone(), patch: Patch::from(&self.patch) };
+        storage.set_string(PATCH_KEY, patch_to_text(&file));
+        storage.set_string(GLOBALS_KEY, globals_to_string(&self.patch));
+        storage.set_string(ENDPOINT_KEY, self.endpoint.lock().unwrap().clone());
+        let bank_path = self.bank.bank.path.as_ref().map(|path| path.display().to_string());
+        storage.set_string(BANK_PATH_KEY, bank_path.unwrap_or_default());
+        storage.set_string(BANK_SLOT_KEY, self.bank.bank.current.to_string());
+        let library_dir = self.library.dir.as_ref().map(|dir| dir.display().to_string());
+        storage.set_string(LIBRARY_DIR_KEY, library_dir.unwrap_or_default());
+        storage.set_string(MIDI_INPUT_KEY, self.midi_input.connected_port().unwrap_or_default().to_string());
+        storage.set_string(MIDI_OUTPUT_KEY, self.midi_output.connected_port().unwrap_or_default().to_string());
+        storage.set_string(THEME_KEY, self.theme.name().to_string());
+        let open: Vec<&str> = self.windows().into_iter().filter(|(_, shown)| **shown).map(|(name, _)| name).collect();
+        storage.set_string(OPEN_WINDOWS_KEY, open.join(","));
+    }
 
     /// Called each time the UI needs repainting, which may be many times per second.
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         // Put your widgets into a `SidePanel`, `TopPanel`, `CentralPanel`, `Window` or `Area`.
         // For inspiration and more examples, go to https://emilk.github.io/egui
-        ctx.set_visuals(Visuals::dark());
+        ctx.set_visuals(self.theme.visuals());
         {
             if let Ok(msg) = self.rx.try_recv() {
+                let global = self.patch.global;
                 self.patch = msg.into();
+                self.patch.global = global;
+                self.history.reset(&self.patch);
             }
 
             let patch = & mut self.patch;
 
             let sender = &self.sender;
 
+            let comparing = self.bank.comparing();
+            let controlled = handle_midi_events(&mut self.midi_input, patch, &mut self.bank.bank, sender, ctx);
+            handle_history_keys(&mut self.history, patch, sender, ctx);
+            handle_patch_files(&mut self.patch_files, patch, &mut self.patch_name, &mut self.patch_info, sender, ctx);
+            handle_bank(&mut self.bank, patch, &mut self.patch_name, sender, ctx);
+            draw_bank(&mut self.bank, patch, &mut self.patch_name, &mut self.midi_output, ctx);
+            let previewed = draw_library(&mut self.library, patch, &mut self.patch_name, &mut self.patch_info, sender, ctx);
+            draw_randomizer(&mut self.randomizer, patch, sender, ctx);
+            let morphed = draw_morph(&mut self.morph, patch, &self.bank.bank, sender, ctx);
+            draw_history(&mut self.history, patch, sender, ctx);
+            draw_mapping_editor(&mut self.midi_input, ctx);
+
+            draw_sequencer(&self.sequencer, &self.clock, &mut self.show_sequencer, ctx);
+            draw_arpeggiator(&self.arpeggiator, &self.clock, &mut self.show_arpeggiator, ctx);
+            draw_parameter_editor(patch, sender, &mut self.show_parameters, ctx);
+            draw_midi_file_player(&mut self.midi_file_player, &self.clock, ctx);
+            draw_recorder(&mut self.recorder, &self.sequencer, &self.clock, ctx);
+
+            let note_sender = &self.note_sender;
+            let midi_input = &mut self.midi_input;
+            let midi_output = &mut self.midi_output;
+            let show_sequencer = &mut self.show_sequencer;
+            let show_arpeggiator = &mut self.show_arpeggiator;
+            let show_midi_file = &mut self.midi_file_player.open;
+            let show_recorder = &mut self.recorder.open;
+            let show_parameters = &mut self.show_parameters;
+            let patch_files = &mut self.patch_files;
+            let bank = &mut self.bank;
+            let show_library = &mut self.library.open;
+            let show_randomizer = &mut self.randomizer.open;
+            let show_morph = &mut self.morph.open;
+            let show_history = &mut self.history.open;
+            let (endpoint, endpoint_text, connection, theme) = (&self.endpoint, &mut self.endpoint_text, &self.connection, &mut self.theme);
+            let mut reset = false;
+            let patch_name = &mut self.patch_name;
+            egui::TopBottomPanel::top("Menu").show(ctx, |ui| {
+                egui::menu::bar(ui, |ui| {
+                    ui.menu_button("MIDI", |ui| draw_midi_menu(midi_input, midi_output, note_sender, ui));
+                    ui.menu_button("Settings", |ui| draw_settings_menu(endpoint, endpoint_text, connection, theme, &mut reset, ui));
+                    ui.toggle_value(show_sequencer, "Sequencer");
+                    ui.toggle_value(show_arpeggiator, "Arp");
+                    ui.toggle_value(show_midi_file, "File");
+                    ui.toggle_value(show_recorder, "Rec");
+                    ui.toggle_value(show_parameters, "Params");
+                    ui.toggle_value(&mut bank.open, "Bank");
+                    ui.toggle_value(show_library, "Library");
+                    ui.toggle_value(show_randomizer, "Random");
+                    ui.toggle_value(show_morph, "Morph");
+                    ui.toggle_value(show_history, "History");
+                });
+            });
+
+            let keyboard = &mut self.keyboard;
+            handle_computer_keyboard(keyboard, ctx, note_sender);
+            egui::TopBottomPanel::bottom("Keyboard").show(ctx, |ui| {
+                draw_keyboard(keyboard, note_sender, ui);
+            });
+
             egui::CentralPanel::default().show(ctx, |mut ui| {
                 egui::Grid::new("OscillatorBank")
                     .min_col_width(70.0)
@@ -407,15 +1006,29 @@ impl eframe::App for BassSynthUI {
                             });
                         });
                     });
-                draw_filter_section(&mut patch.filter, sender, ui);
+                // Global sits beside the filter, the row with room for it, and wraps below when
+                // the window is narrow.
+                ui.horizontal_wrapped(|ui| {
+                    draw_filter_section(&mut patch.filter, sender, ui);
+                    draw_global_section(&mut patch.global, sender, ui);
+                });
                 ui.end_row();
                 ui.horizontal(|ui| {
                     draw_amp_section(&mut patch.amp, sender, ui);
-                    draw_patch_section(ui);
+                    draw_patch_section(patch_files, bank, patch, patch_name, sender, ui);
                 });
             },
             );
 
+            if reset {
+                self.reset_to_defaults(ctx);
+            }
+            // Previews, comparisons and morphs are for listening, so they aren't undo steps.
+            if previewed || morphed || self.bank.comparing() != comparing {
+                self.history.reset(&self.patch);
+            } else {
+                self.history.record(&self.patch, controlled, ctx);
+            }
         }
         /*
                 let mut label =String::new();
@@ -465,3 +1078,138 @@ fn powered_by_egui_and_eframe(ui: &mut egui::Ui) {
         ui.label(".");
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bindings::{FilterModeEnum_HP, FilterModeEnum_LP, WaveformEnum_SAW, WaveformEnum_SIN, WaveformEnum_SQR};
+
+    const WAVEFORMS: [WaveformEnum; 3] = [WaveformEnum_SIN, WaveformEnum_SAW, WaveformEnum_SQR];
+    const FILTER_MODES: [FilterModeEnum; 2] = [FilterModeEnum_HP, FilterModeEnum_LP];
+
+    /// Every field of a patch, with floats as their bits so they compare exactly. Naming each
+    /// field means a field added to `Patch` can't be left out of these tests.
+    fn fields(patch: &Patch) -> Vec<u32> {
+        let Patch {
+            Osc1_Waveform, Osc2_Waveform, Osc3_Waveform, Filter_Mode,
+            Osc1_Coarse, Osc1_Fine, Osc1_Gain,
+            Osc2_Coarse, Osc2_Fine, Osc2_Gain,
+            Osc3_Coarse, Osc3_Fine, Osc3_Gain,
+            Filter_Resonance, Amp_Gain, Filter_Cutoff,
+            Filter_Attack, Filter_Decay, Filter_Sustain, Filter_Release, Filter_Emphasis,
+            Amp_Attack, Amp_Decay, Amp_Sustain, Amp_Release,
+        } = *patch;
+        let bytes = [Osc1_Waveform, Osc2_Waveform, Osc3_Waveform, Filter_Mode, Filter_Resonance];
+        let ints = [Osc1_Coarse, Osc1_Fine, Osc1_Gain, Osc2_Coarse, Osc2_Fine, Osc2_Gain, Osc3_Coarse, Osc3_Fine, Osc3_Gain, Amp_Gain];
+        let floats = [
+            Filter_Cutoff, Filter_Attack, Filter_Decay, Filter_Sustain, Filter_Release, Filter_Emphasis,
+            Amp_Attack, Amp_Decay, Amp_Sustain, Amp_Release,
+        ];
+        bytes.iter().map(|v| *v as u32)
+            .chain(ints.iter().map(|v| *v as u8 as u32))
+            .chain(floats.iter().map(|v| v.to_bits()))
+            .collect()
+    }
+
+    /// A patch with every field of each type set to the same value.
+    fn uniform(waveform: WaveformEnum, filter_mode: FilterModeEnum, int: i8, resonance: u8, float: f32) -> Patch {
+        Patch {
+            Osc1_Waveform: waveform, Osc2_Waveform: waveform, Osc3_Waveform: waveform, Filter_Mode: filter_mode,
+            Osc1_Coarse: int, Osc1_Fine: int, Osc1_Gain: int,
+            Osc2_Coarse: int, Osc2_Fine: int, Osc2_Gain: int,
+            Osc3_Coarse: int, Osc3_Fine: int, Osc3_Gain: int,
+            Filter_Resonance: resonance, Amp_Gain: int, Filter_Cutoff: float,
+            Filter_Attack: float, Filter_Decay: float, Filter_Sustain: float, Filter_Release: float, Filter_Emphasis: float,
+            Amp_Attack: float, Amp_Decay: float, Amp_Sustain: float, Amp_Release: float,
+        }
+    }
+
+    fn assert_round_trips(patch: Patch) {
+        let ui = PatchUI::from(patch);
+        assert_eq!(fields(&Patch::from(&ui)), fields(&patch));
+    }
+
+    #[test]
+    fn patch_round_trips_through_ui_at_the_boundaries() {
+        let ints = [i8::MIN, -50, -24, -1, 0, 1, 24, 50, i8::MAX];
+        let resonances = [0, 1, 128, u8::MAX];
+        let floats = [0.0, -0.0, 1.0, -1.0, 20.0, 20000.0, f32::MIN_POSITIVE, f32::MAX, f32::MIN, f32::INFINITY, f32::NAN];
+        for int in ints {
+            for resonance in resonances {
+                for float in floats {
+                    assert_round_trips(uniform(WaveformEnum_SAW, FilterModeEnum_LP, int, resonance, float));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn patch_round_trips_through_ui_with_every_choice() {
+        for (osc1, osc2, osc3) in WAVEFORMS.iter().flat_map(|a| WAVEFORMS.iter().flat_map(move |b| WAVEFORMS.iter().map(move |c| (*a, *b, *c)))) {
+            for filter_mode in FILTER_MODES {
+                let patch = Patch {
+                    Osc1_Waveform: osc1,
+                    Osc2_Waveform: osc2,
+                    Osc3_Waveform: osc3,
+                    ..uniform(WaveformEnum_SAW, filter_mode, -7, 42, 0.25)
+                };
+                assert_round_trips(patch);
+            }
+        }
+    }
+
+    #[test]
+    fn ui_puts_each_patch_field_under_its_parameter() {
+        // Different values everywhere, so a field read from or written to the wrong place shows.
+        let patch = Patch {
+            Osc1_Waveform: WaveformEnum_SIN, Osc2_Waveform: WaveformEnum_SAW, Osc3_Waveform: WaveformEnum_SQR, Filter_Mode: FilterModeEnum_HP,
+            Osc1_Coarse: -24, Osc1_Fine: -3, Osc1_Gain: -128,
+            Osc2_Coarse: 12, Osc2_Fine: 7, Osc2_Gain: -6,
+            Osc3_Coarse: 24, Osc3_Fine: -50, Osc3_Gain: 6,
+            Filter_Resonance: 201, Amp_Gain: -1, Filter_Cutoff: 1234.0,
+            Filter_Attack: 21.0, Filter_Decay: 22.0, Filter_Sustain: 0.23, Filter_Release: 24.0, Filter_Emphasis: 0.25,
+            Amp_Attack: 26.0, Amp_Decay: 27.0, Amp_Sustain: 0.28, Amp_Release: 29.0,
+        };
+        let expected: [(Section, ParameterType, f32); 25] = [
+            (Section_Osc1, ParameterType_Waveform, WaveformEnum_SIN as f32),
+            (Section_Osc1, ParameterType_Coarse, -24.0),
+            (Section_Osc1, ParameterType_Fine, -3.0),
+            (Section_Osc1, ParameterType_Gain, -128.0),
+            (Section_Osc2, ParameterType_Waveform, WaveformEnum_SAW as f32),
+            (Section_Osc2, ParameterType_Coarse, 12.0),
+            (Section_Osc2, ParameterType_Fine, 7.0),
+            (Section_Osc2, ParameterType_Gain, -6.0),
+            (Section_Osc3, ParameterType_Waveform, WaveformEnum_SQR as f32),
+            (Section_Osc3, ParameterType_Coarse, 24.0),
+            (Section_Osc3, ParameterType_Fine, -50.0),
+            (Section_Osc3, ParameterType_Gain, 6.0),
+            (Section_Filter, ParameterType_Mode, FilterModeEnum_HP as f32),
+            (Section_Filter, ParameterType_Cutoff, 1234.0),
+            (Section_Filter, ParameterType_Resonance, 201.0),
+            (Section_Filter, ParameterType_Emphasis, 0.25),
+            (Section_Filter, ParameterType_Attack, 21.0),
+            (Section_Filter, ParameterType_Decay, 22.0),
+            (Section_Filter, ParameterType_Sustain, 0.23),
+            (Section_Filter, ParameterType_Release, 24.0),
+            (Section_Amp, ParameterType_Gain, -1.0),
+            (Section_Amp, ParameterType_Attack, 26.0),
+            (Section_Amp, ParameterType_Decay, 27.0),
+            (Section_Amp, ParameterType_Sustain, 0.28),
+            (Section_Amp, ParameterType_Release, 29.0),
+        ];
+        let ui = PatchUI::from(patch);
+        for (section, parameter, value) in expected {
+            assert_eq!(ui.get(section, parameter), Some(value), "{}", params::param(section, parameter).full_name());
+        }
+        let patch_params = params::PARAMS.iter().filter(|desc| desc.section != Section_Global).count();
+        assert_eq!(patch_params, expected.len(), "every patch parameter is checked");
+    }
+
+    #[test]
+    fn patch_keeps_the_default_globals() {
+        let ui = PatchUI::from(uniform(WaveformEnum_SAW, FilterModeEnum_LP, 0, 0, 0.0));
+        for desc in params::PARAMS.iter().filter(|desc| desc.section == Section_Global) {
+            assert_eq!(ui.get(desc.section, desc.parameter), Some(desc.default), "{}", desc.full_name());
+        }
+    }
+}
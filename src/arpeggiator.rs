@@ -0,0 +1,243 @@
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::app::{Message, Note};
+use crate::clock::{Clock, MAX_WAIT};
+
+const IDLE_POLL: Duration = Duration::from_millis(20);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArpMode {
+    Up,
+    Down,
+    UpDown,
+    Random,
+    AsPlayed,
+}
+
+impl ArpMode {
+    pub const ALL: [ArpMode; 5] = [ArpMode::Up, ArpMode::Down, ArpMode::UpDown, ArpMode::Random, ArpMode::AsPlayed];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ArpMode::Up => "Up",
+            ArpMode::Down => "Down",
+            ArpMode::UpDown => "Up/Down",
+            ArpMode::Random => "Random",
+            ArpMode::AsPlayed => "As played",
+        }
+    }
+}
+
+/// Note length as a fraction of a beat.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ArpRate {
+    pub name: &'static str,
+    pub beats: f64,
+}
+
+pub const ARP_RATES: [ArpRate; 6] = [
+    ArpRate { name: "1/4", beats: 1.0 },
+    ArpRate { name: "1/8", beats: 0.5 },
+    ArpRate { name: "1/8T", beats: 1.0 / 3.0 },
+    ArpRate { name: "1/16", beats: 0.25 },
+    ArpRate { name: "1/16T", beats: 1.0 / 6.0 },
+    ArpRate { name: "1/32", beats: 0.125 },
+];
+
+/// Settings shared between the UI and the arpeggiator thread.
+pub struct ArpState {
+    pub enabled: bool,
+    pub mode: ArpMode,
+    pub octaves: u8,
+    pub rate: ArpRate,
+    /// Fraction of each step the note is held for.
+    pub gate: f32,
+    /// Keep arpeggiating after the keys are released, until a new chord is played.
+    pub latch: bool,
+}
+
+impl Default for ArpState {
+    fn default() -> Self {
+        ArpState {
+            enabled: false,
+            mode: ArpMode::Up,
+            octaves: 1,
+            rate: ARP_RATES[3],
+            gate: 0.5,
+            latch: false,
+        }
+    }
+}
+
+/// The notes to cycle through for the held chord, in playing order.
+fn arp_sequence(held: &[(Note, u8)], mode: ArpMode, octaves: u8) -> Vec<(Note, u8)> {
+    let mut notes = held.to_vec();
+    if mode != ArpMode::AsPlayed {
+        notes.sort_by_key(|(note, _)| *note);
+    }
+    let mut sequence: Vec<(Note, u8)> = (0..octaves)
+        .flat_map(|octave| notes.iter().map(move |&(note, vel)| (note.saturating_add(12 * octave), vel)))
+        .filter(|(note, _)| *note <= 127)
+        .collect();
+    match mode {
+        ArpMode::Down => sequence.reverse(),
+        ArpMode::UpDown if sequence.len() > 2 => {
+            let down: Vec<_> = sequence[1..sequence.len() - 1].iter().rev().copied().collect();
+            sequence.extend(down);
+        }
+        _ => {}
+    }
+    sequence
+}
+
+/// Small xorshift generator for the random mode; it only needs to sound random.
+fn next_random(seed: &mut u32) -> u32 {
+    *seed ^= *seed << 13;
+    *seed ^= *seed >> 17;
+    *seed ^= *seed << 5;
+    *seed
+}
+
+/// Sits between the note inputs and the synth. While the arpeggiator is off notes are passed
+/// straight through; while it is on the held notes are played in turn on the clock's beats.
+/// It plays whenever notes are held on the internal clock, in time with the sequencer if
+/// that is playing, but only while the transport runs on an external clock.
+pub fn run_arpeggiator(
+    rx: Receiver<Message>,
+    shared: Arc<Mutex<ArpState>>,
+    clock: Arc<Mutex<Clock>>,
+    sender: Sender<Message>,
+) {
+    // Keys physically down, and the notes being arpeggiated (which outlive them when latched).
+    let mut pressed: Vec<Note> = Vec::new();
+    let mut held: Vec<(Note, u8)> = Vec::new();
+    let mut sounding: Option<Note> = None;
+    // Beats at which the next note starts and the sounding one ends.
+    let mut next_tick: Option<f64> = None;
+    let mut gate_off: Option<f64> = None;
+    let mut position = 0;
+    let mut seed = 0x2545_f491;
+
+    loop {
+        let deadline = [next_tick, gate_off].into_iter().flatten().reduce(f64::min);
+        let timeout = deadline.map_or(IDLE_POLL, |beat| {
+            clock.lock().unwrap().time_of(beat).saturating_duration_since(Instant::now()).min(MAX_WAIT)
+        });
+        let received = rx.recv_timeout(timeout);
+        let state = shared.lock().unwrap();
+        let mut messages = Vec::new();
+
+        match received {
+            Ok(Message::NoteOn(note, vel, _)) if state.enabled => {
+                if state.latch && pressed.is_empty() {
+                    held.clear();
+                }
+                pressed.push(note);
+                held.retain(|(n, _)| *n != note);
+                held.push((note, vel));
+            }
+            // A note pressed before the arpeggiator was switched on was played directly, so its
+            // release goes through too.
+            Ok(Message::NoteOff(note, _)) if state.enabled && pressed.contains(&note) => {
+                pressed.retain(|n| *n != note);
+                if !state.latch {
+                    held.retain(|(n, _)| *n != note);
+                }
+            }
+            Ok(msg) => messages.push(msg),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        if !state.latch && pressed.is_empty() {
+            held.clear();
+        }
+        if !state.enabled {
+            pressed.clear();
+            held.clear();
+        }
+
+        let mut clock = clock.lock().unwrap();
+        let beat = clock.advance();
+        let stopped = clock.is_external() && !clock.running;
+        let now = Instant::now();
+        if gate_off.is_some_and(|g| g <= beat) || held.is_empty() || stopped {
+            gate_off = None;
+            messages.extend(sounding.take().map(|note| Message::NoteOff(note, now)));
+        }
+        if held.is_empty() || stopped {
+            next_tick = None;
+        } else if next_tick.is_none() {
+            // Wait for the next note on the grid while the transport runs.
+            next_tick = Some(if clock.running { (beat / state.rate.beats).ceil() * state.rate.beats } else { beat });
+            position = 0;
+        }
+        if let Some(tick) = next_tick.filter(|t| *t <= beat) {
+            let sequence = arp_sequence(&held, state.mode, state.octaves);
+            let index = if state.mode == ArpMode::Random {
+                next_random(&mut seed) as usize % sequence.len()
+            } else {
+                position % sequence.len()
+            };
+            position += 1;
+            let (note, vel) = sequence[index];
+            messages.extend(sounding.take().map(|note| Message::NoteOff(note, now)));
+            messages.push(Message::NoteOn(note, vel, now));
+            sounding = Some(note);
+
+            let step = state.rate.beats;
+            gate_off = Some(tick + step * state.gate.clamp(0.05, 1.0) as f64);
+            // Skip ahead rather than racing to catch up if the thread fell behind.
+            next_tick = Some(if tick + step < beat { tick + ((beat - tick) / step).ceil() * step } else { tick + step });
+        }
+        drop(clock);
+        drop(state);
+
+        for msg in messages {
+            if sender.send(msg).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+pub fn draw_arpeggiator(shared: &Mutex<ArpState>, clock: &Mutex<Clock>, open: &mut bool, ctx: &egui::Context) {
+    egui::Window::new("Arpeggiator").open(open).show(ctx, |ui| {
+        let mut state = shared.lock().unwrap();
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut state.enabled, "On");
+            ui.toggle_value(&mut state.latch, "Latch");
+            ui.add(egui::DragValue::new(&mut clock.lock().unwrap().tempo).range(40.0..=300.0).suffix(" bpm"));
+        });
+        egui::Grid::new("Arpeggiator").num_columns(2).show(ui, |ui| {
+            ui.label("Mode");
+            egui::ComboBox::from_id_source("Arp mode")
+                .selected_text(state.mode.name())
+                .show_ui(ui, |ui| {
+                    for mode in ArpMode::ALL {
+                        ui.selectable_value(&mut state.mode, mode, mode.name());
+                    }
+                });
+            ui.end_row();
+
+            ui.label("Rate");
+            egui::ComboBox::from_id_source("Arp rate")
+                .selected_text(state.rate.name)
+                .show_ui(ui, |ui| {
+                    for rate in ARP_RATES {
+                        ui.selectable_value(&mut state.rate, rate, rate.name);
+                    }
+                });
+            ui.end_row();
+
+            ui.label("Octaves");
+            ui.add(egui::Slider::new(&mut state.octaves, 1..=4));
+            ui.end_row();
+
+            ui.label("Gate");
+            ui.add(egui::Slider::new(&mut state.gate, 0.05..=1.0));
+            ui.end_row();
+        });
+    });
+}
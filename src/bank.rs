@@ -0,0 +1,609 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use egui::ViewportCommand;
+use egui_file_dialog::{DialogMode, FileDialog};
+use crate::app::{Message, PatchUI};
+use crate::binary::{decode_bank, encode_bank, is_bank, is_binary_path, BINARY_EXTENSION};
+use crate::bindings::Patch;
+use crate::sysex::{decode_dumps, encode_dump, is_sysex, is_sysex_path, DumpSlot, SYSEX_EXTENSION};
+use crate::midi::MidiOutputState;
+use crate::patch::{content_lines, diff_patches, key_value, read_version, write_patch_body, PatchReader, PATCH_FORMAT_VERSION};
+
+pub const BANK_SIZE: usize = 32;
+const BANK_HEADER: &str = "# BassSynth bank";
+const INIT_NAME: &str = "Init";
+
+#[derive(Clone)]
+pub struct BankPatch {
+    pub name: String,
+    pub patch: Patch,
+}
+
+impl BankPatch {
+    pub fn init() -> Self {
+        BankPatch { name: INIT_NAME.to_string(), patch: Patch::from(&PatchUI::default()) }
+    }
+
+    /// A patch from a format without names, named after its slot.
+    pub fn numbered(index: usize, patch: Patch) -> Self {
+        BankPatch { name: format!("Patch {}", index + 1), patch }
+    }
+}
+
+/// Places SysEx dumps in the slots they were dumped from, up to the highest one, leaving the
+/// others initialised.
+fn bank_from_dumps(dumps: Vec<(DumpSlot, Patch)>) -> Result<Vec<BankPatch>, String> {
+    let mut patches = Vec::new();
+    for (number, (slot, patch)) in dumps.into_iter().enumerate() {
+        let DumpSlot::Bank(index) = slot else {
+            return Err(format!("Dump {} is a single patch: open it as a patch", number + 1));
+        };
+        if index >= patches.len() {
+            patches.resize(index + 1, BankPatch::init());
+        }
+        patches[index] = BankPatch::numbered(index, patch);
+    }
+    Ok(patches)
+}
+
+/// Writes a bank as a header followed by each patch in the patch file format, under a
+/// numbered `[[Patch n]]` heading.
+pub fn bank_to_text(patches: &[BankPatch]) -> String {
+    let mut text = format!("{}\nversion = {}\n", BANK_HEADER, PATCH_FORMAT_VERSION);
+    for (index, bank_patch) in patches.iter().enumerate() {
+        text += &format!("\n[[Patch {}]]\n", index + 1);
+        write_patch_body(&mut text, &bank_patch.name, &bank_patch.patch);
+    }
+    text
+}
+
+pub fn bank_from_text(text: &str) -> Result<Vec<BankPatch>, String> {
+    let mut patches = Vec::new();
+    let mut reader: Option<PatchReader> = None;
+    let mut version = None;
+    let finish = |reader: PatchReader| {
+        let file = reader.finish();
+        BankPatch { name: file.name, patch: file.patch }
+    };
+    for (number, line) in content_lines(text) {
+        let err = |e: String| format!("Line {}: {}", number, e);
+        if let Some(heading) = line.strip_prefix("[[").and_then(|l| l.strip_suffix("]]")) {
+            patches.extend(reader.take().map(finish));
+            let expected = format!("Patch {}", patches.len() + 1);
+            if heading.trim() != expected {
+                return Err(err(format!("Expected [[{}]], found [[{}]]", expected, heading)));
+            }
+            reader = Some(PatchReader::default());
+            continue;
+        }
+        let handled = match &mut reader {
+            Some(reader) => reader.read_line(line).map_err(err)?,
+            None => false,
+        };
+        if !handled {
+            match key_value(line).map_err(err)? {
+                ("version", value) if reader.is_none() => version = Some(read_version(value).map_err(err)?),
+                (key, _) => return Err(err(format!("Unknown setting \"{}\"", key))),
+            }
+        }
+    }
+    patches.extend(reader.map(finish));
+    if version.is_none() {
+        return Err("Not a bank file: the format version is missing".to_string());
+    }
+    if patches.is_empty() {
+        return Err("The bank has no patches".to_string());
+    }
+    Ok(patches)
+}
+
+/// Numbered patches, recalled into the patch being edited.
+pub struct PatchBank {
+    pub path: Option<PathBuf>,
+    pub patches: Vec<BankPatch>,
+    pub current: usize,
+    /// The bank has changed since it was last saved.
+    pub dirty: bool,
+}
+
+impl Default for PatchBank {
+    fn default() -> Self {
+        PatchBank { path: None, patches: vec![BankPatch::init(); BANK_SIZE], current: 0, dirty: false }
+    }
+}
+
+impl PatchBank {
+    /// Loads a bank file, a packed bank in the firmware's binary format or SysEx bank dumps. The
+    /// last two name their patches by number since they carry no names.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let bytes = std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let patches = if is_bank(&bytes) {
+            decode_bank(&bytes).map(|patches| patches.into_iter().enumerate().map(|(index, patch)| BankPatch::numbered(index, patch)).collect())
+        } else if is_sysex(&bytes) {
+            decode_dumps(&bytes).and_then(bank_from_dumps)
+        } else {
+            String::from_utf8(bytes)
+                .map_err(|_| "Not a bank file".to_string())
+                .and_then(|text| bank_from_text(&text))
+        };
+        let patches = patches.map_err(|e| format!("{}: {}", path.display(), e))?;
+        Ok(PatchBank { path: Some(path.to_path_buf()), patches, current: 0, dirty: false })
+    }
+
+    /// Saves the bank, packed in the binary format or as SysEx dumps if the path has the binary
+    /// or SysEx extension.
+    pub fn save(&mut self, path: &Path) -> Result<(), String> {
+        let bytes = if is_binary_path(path) {
+            let patches: Vec<Patch> = self.patches.iter().map(|p| p.patch).collect();
+            encode_bank(&patches)?
+        } else if is_sysex_path(path) {
+            self.dumps()?.concat()
+        } else {
+            bank_to_text(&self.patches).into_bytes()
+        };
+        std::fs::write(path, bytes).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        self.path = Some(path.to_path_buf());
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// A SysEx dump of each slot, if the bank is small enough to dump.
+    pub fn dumps(&self) -> Result<Vec<Vec<u8>>, String> {
+        self.patches.iter().enumerate().map(|(index, p)| encode_dump(DumpSlot::Bank(index), &p.patch)).collect()
+    }
+
+    pub fn title(&self) -> String {
+        let name = self.path.as_deref()
+            .and_then(Path::file_stem)
+            .map_or("Untitled".into(), |stem| stem.to_string_lossy());
+        format!("{}{}", name, if self.dirty { "*" } else { "" })
+    }
+
+    /// Whether the patch being edited differs from the one stored in the current slot.
+    pub fn is_edited(&self, patch: &PatchUI, name: &str) -> bool {
+        let stored = &self.patches[self.current];
+        stored.name != name || !diff_patches(&stored.patch, &Patch::from(patch)).is_empty()
+    }
+
+    /// Recalls a slot into the patch being edited, returning the messages that send it to the synth.
+    pub fn select(&mut self, index: usize, patch: &mut PatchUI, name: &mut String) -> Vec<Message> {
+        self.current = index.min(self.patches.len() - 1);
+        let stored = &self.patches[self.current];
+        name.clone_from(&stored.name);
+        patch.load(stored.patch)
+    }
+
+    pub fn store(&mut self, patch: &PatchUI, name: &str) {
+        self.patches[self.current] = BankPatch { name: name.to_string(), patch: Patch::from(patch) };
+        self.dirty = true;
+    }
+
+    pub fn copy(&mut self, from: usize, to: usize) {
+        self.patches[to] = self.patches[from].clone();
+        self.dirty = true;
+    }
+
+    pub fn swap(&mut self, a: usize, b: usize) {
+        self.patches.swap(a, b);
+        self.dirty = true;
+    }
+
+    pub fn init(&mut self, index: usize) {
+        self.patches[index] = BankPatch::init();
+        self.dirty = true;
+    }
+}
+
+/// Something that would lose edits, waiting for the user to confirm it.
+#[derive(Clone, Copy, PartialEq)]
+enum Pending {
+    Select(usize),
+    New,
+    Open,
+    Quit,
+}
+
+pub struct BankWindow {
+    pub bank: PatchBank,
+    pub open: bool,
+    dialog: FileDialog,
+    pending: Option<Pending>,
+    /// Slot that Copy and Swap act on along with the current one.
+    target: usize,
+    quitting: bool,
+    /// The edited patch, set aside while the stored one plays for comparison.
+    compare: Option<(String, Patch)>,
+    /// Whether the patch being edited differs from the current slot, worked out by
+    /// `handle_bank` once a frame since it compares every parameter.
+    pub edited: bool,
+    error: Option<String>,
+}
+
+impl BankWindow {
+    pub fn new(bank: PatchBank) -> Self {
+        BankWindow { bank, open: false, dialog: FileDialog::new(), pending: None, target: 0, quitting: false, compare: None, edited: false, error: None }
+    }
+
+    /// Swaps in another bank, keeping the Copy and Swap target inside it.
+    pub fn set_bank(&mut self, bank: PatchBank) {
+        self.target = self.target.min(bank.patches.len().saturating_sub(1));
+        self.bank = bank;
+    }
+
+    /// Asks to recall another slot; confirmed first if the current patch has been edited.
+    pub fn request_select(&mut self, index: usize) {
+        self.pending = Some(Pending::Select(index));
+    }
+
+    pub fn comparing(&self) -> bool {
+        self.compare.is_some()
+    }
+
+    /// Switches between the edited patch and the one stored in the current slot.
+    pub fn toggle_compare(&mut self, patch: &mut PatchUI, name: &mut String, sender: &Sender<Message>) {
+        let messages = match self.compare.take() {
+            Some((edited_name, edited)) => {
+                *name = edited_name;
+                patch.load(edited)
+            }
+            None => {
+                self.compare = Some((name.clone(), Patch::from(&*patch)));
+                let stored = &self.bank.patches[self.bank.current];
+                name.clone_from(&stored.name);
+                patch.load(stored.patch)
+            }
+        };
+        for msg in messages {
+            sender.send(msg).unwrap();
+        }
+    }
+
+    /// Stores the edited patch in the current slot.
+    pub fn commit(&mut self, patch: &mut PatchUI, name: &mut String, sender: &Sender<Message>) {
+        if self.comparing() {
+            self.toggle_compare(patch, name, sender);
+        }
+        self.bank.store(patch, name);
+    }
+
+    /// Throws the edit away, going back to the patch stored in the current slot.
+    pub fn discard(&mut self, patch: &mut PatchUI, name: &mut String, sender: &Sender<Message>) {
+        self.compare = None;
+        for msg in self.bank.select(self.bank.current, patch, name) {
+            sender.send(msg).unwrap();
+        }
+    }
+}
+
+fn perform(window: &mut BankWindow, action: Pending, patch: &mut PatchUI, name: &mut String, sender: &Sender<Message>, ctx: &egui::Context) {
+    let messages = match action {
+        Pending::Select(index) => window.bank.select(index, patch, name),
+        Pending::New => {
+            window.set_bank(PatchBank::default());
+            window.bank.select(0, patch, name)
+        }
+        Pending::Open => {
+            window.dialog.select_file();
+            Vec::new()
+        }
+        Pending::Quit => {
+            window.quitting = true;
+            ctx.send_viewport_cmd(ViewportCommand::Close);
+            Vec::new()
+        }
+    };
+    for msg in messages {
+        sender.send(msg).unwrap();
+    }
+}
+
+/// Runs the bank's file dialog and carries out requested actions, asking first when they would
+/// throw away unsaved edits to the patch or the bank.
+pub fn handle_bank(window: &mut BankWindow, patch: &mut PatchUI, name: &mut String, sender: &Sender<Message>, ctx: &egui::Context) {
+    window.edited = window.bank.is_edited(patch, name);
+    // Changing the stored patch while comparing starts a new edit from it, as on hardware, and
+    // anything that could replace the edit brings it back first so it gets the usual prompt.
+    if window.comparing() && window.edited {
+        window.compare = None;
+    }
+    if window.comparing() && window.pending.is_some() {
+        window.toggle_compare(patch, name, sender);
+        window.edited = window.bank.is_edited(patch, name);
+    }
+
+    window.dialog.update(ctx);
+    if let Some(path) = window.dialog.take_selected() {
+        let result = if window.dialog.mode() == DialogMode::SaveFile {
+            window.bank.save(&path)
+        } else {
+            PatchBank::load(&path).map(|bank| {
+                window.set_bank(bank);
+                for msg in window.bank.select(0, patch, name) {
+                    sender.send(msg).unwrap();
+                }
+            })
+        };
+        window.error = result.err();
+    }
+
+    let unsaved_bank = window.bank.dirty || window.edited;
+    if ctx.input(|i| i.viewport().close_requested()) && unsaved_bank && !window.quitting {
+        ctx.send_viewport_cmd(ViewportCommand::CancelClose);
+        window.pending = Some(Pending::Quit);
+    }
+
+    let Some(action) = window.pending else {
+        return;
+    };
+    let edited = window.edited;
+    let unsaved = match action {
+        Pending::Select(index) if index == window.bank.current && !edited => {
+            window.pending = None;
+            return;
+        }
+        Pending::Select(_) => edited,
+        Pending::New | Pending::Open | Pending::Quit => unsaved_bank,
+    };
+    if !unsaved {
+        window.pending = None;
+        perform(window, action, patch, name, sender, ctx);
+        return;
+    }
+
+    let mut choice = None;
+    egui::Window::new("Unsaved changes").collapsible(false).resizable(false).show(ctx, |ui| {
+        if let Pending::Select(_) = action {
+            ui.label(format!("Patch {} has been edited.", window.bank.current + 1));
+            ui.horizontal(|ui| {
+                if ui.button("Store").clicked() {
+                    window.bank.store(patch, name);
+                    choice = Some(true);
+                }
+                if ui.button("Discard").clicked() {
+                    choice = Some(true);
+                }
+                if ui.button("Cancel").clicked() {
+                    choice = Some(false);
+                }
+            });
+        } else {
+            if edited {
+                ui.label(format!("Patch {} has been edited.", window.bank.current + 1));
+            }
+            if window.bank.dirty {
+                ui.label(format!("The bank {} has unsaved changes.", window.bank.title()));
+            }
+            ui.horizontal(|ui| {
+                let path = window.bank.path.clone();
+                let save = ui.add_enabled(path.is_some(), egui::Button::new("Save"))
+                    .on_hover_text(if edited { "Store the patch and save the bank" } else { "Save the bank" });
+                if save.clicked() {
+                    if let Some(path) = path {
+                        if edited {
+                            window.bank.store(patch, name);
+                        }
+                        match window.bank.save(&path) {
+                            Ok(()) => choice = Some(true),
+                            Err(e) => {
+                                window.error = Some(e);
+                                choice = Some(false);
+                            }
+                        }
+                    }
+                }
+                if ui.button("Discard").clicked() {
+                    choice = Some(true);
+                }
+                if ui.button("Cancel").clicked() {
+                    choice = Some(false);
+                }
+            });
+        }
+    });
+    match choice {
+        Some(true) => {
+            window.pending = None;
+            perform(window, action, patch, name, sender, ctx);
+        }
+        Some(false) => window.pending = None,
+        None => {}
+    }
+}
+
+fn slot_label(bank: &PatchBank, index: usize) -> String {
+    format!("{} {}", index + 1, bank.patches[index].name)
+}
+
+pub fn slot_combo(window: &mut BankWindow, id: &str, ui: &mut egui::Ui) {
+    let mut selected = window.bank.current;
+    egui::ComboBox::from_id_source(id)
+        .selected_text(format!("{}", selected + 1))
+        .width(40.0)
+        .show_ui(ui, |ui| {
+            for index in 0..window.bank.patches.len() {
+                ui.selectable_value(&mut selected, index, slot_label(&window.bank, index));
+            }
+        });
+    if selected != window.bank.current {
+        window.request_select(selected);
+    }
+}
+
+pub fn draw_bank(window: &mut BankWindow, patch: &PatchUI, name: &mut String, output: &mut MidiOutputState, ctx: &egui::Context) {
+    let mut open = window.open;
+    egui::Window::new("Bank").open(&mut open).show(ctx, |ui| {
+        ui.horizontal(|ui| {
+            ui.strong(window.bank.title());
+            if ui.button("New").clicked() {
+                window.pending = Some(Pending::New);
+            }
+            if ui.button("Open").clicked() {
+                window.pending = Some(Pending::Open);
+            }
+            if ui.button("Save").clicked() {
+                match window.bank.path.clone() {
+                    Some(path) => window.error = window.bank.save(&path).err(),
+                    None => window.dialog.save_file(),
+                }
+            }
+            let save_as = ui.button("Save as")
+                .on_hover_text(format!("Save with the .{} extension for the firmware's binary format, or .{} for SysEx", BINARY_EXTENSION, SYSEX_EXTENSION));
+            if save_as.clicked() {
+                window.dialog.save_file();
+            }
+        });
+        ui.separator();
+
+        let current = window.bank.current;
+        let edited = window.edited;
+        let target = &mut window.target;
+        let bank = &mut window.bank;
+        ui.horizontal(|ui| {
+            if ui.add_enabled(edited, egui::Button::new(format!("Store to {}", current + 1))).clicked() {
+                bank.store(patch, name);
+            }
+            if ui.button("Init").clicked() {
+                bank.init(current);
+            }
+        });
+        ui.horizontal(|ui| {
+            if ui.button("Copy to").clicked() {
+                bank.copy(current, *target);
+            }
+            if ui.button("Swap with").clicked() {
+                bank.swap(current, *target);
+            }
+            egui::ComboBox::from_id_source("Bank target")
+                .selected_text(format!("{}", *target + 1))
+                .width(40.0)
+                .show_ui(ui, |ui| {
+                    for index in 0..bank.patches.len() {
+                        ui.selectable_value(target, index, slot_label(bank, index));
+                    }
+                });
+        });
+        ui.horizontal(|ui| {
+            let connected = output.connected_port().is_some();
+            ui.label("SysEx to MIDI out:");
+            let mut dumps = None;
+            if ui.add_enabled(connected, egui::Button::new("Send patch")).clicked() {
+                dumps = Some(encode_dump(DumpSlot::EditBuffer, &Patch::from(patch)).map(|dump| vec![dump]));
+            }
+            if ui.add_enabled(connected, egui::Button::new("Send bank")).clicked() {
+                dumps = Some(bank.dumps());
+            }
+            match dumps {
+                Some(Ok(dumps)) => output.send_sysex(&dumps),
+                Some(Err(e)) => window.error = Some(e),
+                None => {}
+            }
+        });
+        ui.separator();
+
+        let mut selected = None;
+        egui::ScrollArea::vertical().max_height(300.0).show(ui, |ui| {
+            egui::Grid::new("Bank slots").num_columns(2).striped(true).show(ui, |ui| {
+                for index in 0..bank.patches.len() {
+                    if ui.selectable_label(index == current, format!("{}", index + 1)).clicked() {
+                        selected = Some(index);
+                    }
+                    if ui.text_edit_singleline(&mut bank.patches[index].name).changed() {
+                        bank.dirty = true;
+                        // Renaming the current slot renames the patch being edited too.
+                        if index == current {
+                            name.clone_from(&bank.patches[index].name);
+                        }
+                    }
+                    ui.end_row();
+                }
+            });
+        });
+        if let Some(index) = selected {
+            window.request_select(index);
+        }
+        if let Some(error) = &window.error {
+            ui.colored_label(ui.visuals().error_fg_color, error.as_str());
+        }
+    });
+    window.open = open;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::binary::encode_patch;
+    use crate::test_util::sample_patch;
+
+    /// A bank whose patches each have every parameter somewhere different in its range.
+    fn sample_bank(size: usize) -> Vec<BankPatch> {
+        (0..size).map(|index| BankPatch { name: format!("Slot {} = [x]", index + 1), patch: sample_patch(index as f32 * 0.31) }).collect()
+    }
+
+    fn assert_same_patches(a: &[BankPatch], b: &[BankPatch]) {
+        assert_eq!(a.len(), b.len());
+        for (a, b) in a.iter().zip(b) {
+            assert_eq!(a.name, b.name);
+            assert_eq!(encode_patch(&a.patch), encode_patch(&b.patch));
+        }
+    }
+
+    #[test]
+    fn bank_text_round_trips() {
+        for size in [1, 2, BANK_SIZE, BANK_SIZE + 5] {
+            let patches = sample_bank(size);
+            assert_same_patches(&bank_from_text(&bank_to_text(&patches)).unwrap(), &patches);
+        }
+        let init = vec![BankPatch::init(); BANK_SIZE];
+        assert_same_patches(&bank_from_text(&bank_to_text(&init)).unwrap(), &init);
+    }
+
+    #[test]
+    fn bad_bank_text_is_rejected() {
+        let text = bank_to_text(&sample_bank(3));
+        assert!(bank_from_text(&text.replace("[[Patch 2]]", "[[Patch 3]]")).is_err());
+        assert!(bank_from_text(&text.replace(&format!("version = {}\n", PATCH_FORMAT_VERSION), "")).is_err());
+        assert!(bank_from_text(&format!("{}\nversion = {}\n", BANK_HEADER, PATCH_FORMAT_VERSION)).is_err());
+        assert!(bank_from_text(&text.replace("[Filter]", "[Phaser]")).is_err());
+        assert!(bank_from_text(&format!("{}\nversion = 1\n", text)).is_err());
+    }
+
+    #[test]
+    fn loading_a_smaller_bank_keeps_the_target_inside_it() {
+        let path = std::env::temp_dir().join(format!("bass_synth_ui_small_bank_{}.txt", std::process::id()));
+        let mut small = PatchBank { patches: sample_bank(3), ..PatchBank::default() };
+        small.save(&path).unwrap();
+        let mut window = BankWindow::new(PatchBank::default());
+        window.target = 20;
+        window.set_bank(PatchBank::load(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(window.target, 2);
+        window.bank.copy(window.bank.current, window.target);
+        window.bank.swap(window.bank.current, window.target);
+        assert_eq!(window.bank.patches.len(), 3);
+    }
+
+    #[test]
+    fn banks_from_dumps_are_as_big_as_the_highest_slot() {
+        let patches = sample_bank(3);
+        let dumps = vec![(DumpSlot::Bank(0), patches[0].patch), (DumpSlot::Bank(40), patches[1].patch), (DumpSlot::Bank(5), patches[2].patch)];
+        let bank = bank_from_dumps(dumps).unwrap();
+        assert_eq!(bank.len(), 41);
+        assert_eq!(encode_patch(&bank[40].patch), encode_patch(&patches[1].patch));
+        assert_eq!(bank[40].name, "Patch 41");
+        assert_eq!(bank[1].name, INIT_NAME);
+        assert!(bank_from_dumps(vec![(DumpSlot::EditBuffer, patches[0].patch)]).is_err());
+    }
+
+    #[test]
+    fn bank_dumps_round_trip() {
+        let bank = PatchBank { patches: sample_bank(BANK_SIZE + 3), ..PatchBank::default() };
+        let dumps = decode_dumps(&bank.dumps().unwrap().concat()).unwrap();
+        let patches: Vec<BankPatch> = bank_from_dumps(dumps).unwrap();
+        assert_eq!(patches.len(), bank.patches.len());
+        for (a, b) in patches.iter().zip(&bank.patches) {
+            assert_eq!(encode_patch(&a.patch), encode_patch(&b.patch));
+        }
+        let too_big = PatchBank { patches: sample_bank(200), ..PatchBank::default() };
+        assert!(too_big.dumps().is_err());
+    }
+}
@@ -0,0 +1,266 @@
+use std::mem::size_of;
+use std::path::Path;
+use crate::app::PatchUI;
+use crate::bindings::{Patch, Section_Global};
+use crate::params::PARAMS;
+use crate::synth::{FilterMode, Waveform};
+
+/// Size of one `Patch` as the firmware lays it out.
+pub const PATCH_RECORD_SIZE: usize = 56;
+const _: () = assert!(size_of::<Patch>() == PATCH_RECORD_SIZE);
+
+/// Identifies a packed bank, followed by the header's other fields.
+const BANK_MAGIC: [u8; 4] = *b"BSPB";
+/// Bump when the record layout changes; banks from newer versions are refused.
+pub const PROTOCOL_VERSION: u16 = 1;
+/// Magic, protocol version (u16), patch count (u16) and CRC-32 of the records (u32).
+const BANK_HEADER_SIZE: usize = 12;
+/// Files saved with this extension are written in the binary format.
+pub const BINARY_EXTENSION: &str = "bin";
+
+pub fn is_binary_path(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case(BINARY_EXTENSION))
+}
+
+/// Writes a patch exactly as the firmware stores it: little-endian, with the padding byte after
+/// `Amp_Gain` zeroed.
+pub fn encode_patch(patch: &Patch) -> [u8; PATCH_RECORD_SIZE] {
+    let mut bytes = [0; PATCH_RECORD_SIZE];
+    bytes[..15].copy_from_slice(&[
+        patch.Osc1_Waveform,
+        patch.Osc2_Waveform,
+        patch.Osc3_Waveform,
+        patch.Filter_Mode,
+        patch.Osc1_Coarse as u8,
+        patch.Osc1_Fine as u8,
+        patch.Osc1_Gain as u8,
+        patch.Osc2_Coarse as u8,
+        patch.Osc2_Fine as u8,
+        patch.Osc2_Gain as u8,
+        patch.Osc3_Coarse as u8,
+        patch.Osc3_Fine as u8,
+        patch.Osc3_Gain as u8,
+        patch.Filter_Resonance,
+        patch.Amp_Gain as u8,
+    ]);
+    let floats = [
+        patch.Filter_Cutoff,
+        patch.Filter_Attack,
+        patch.Filter_Decay,
+        patch.Filter_Sustain,
+        patch.Filter_Release,
+        patch.Filter_Emphasis,
+        patch.Amp_Attack,
+        patch.Amp_Decay,
+        patch.Amp_Sustain,
+        patch.Amp_Release,
+    ];
+    for (chunk, value) in bytes[16..].chunks_exact_mut(4).zip(floats) {
+        chunk.copy_from_slice(&value.to_le_bytes());
+    }
+    bytes
+}
+
+/// Reads a record written by `encode_patch`, refusing unknown waveforms and filter modes and
+/// values that aren't numbers or are outside their parameter's range.
+pub fn decode_patch(bytes: &[u8]) -> Result<Patch, String> {
+    if bytes.len() != PATCH_RECORD_SIZE {
+        return Err(format!("A patch record is {} bytes, not {}", PATCH_RECORD_SIZE, bytes.len()));
+    }
+    let waveform = |index: usize| Waveform::try_from(bytes[index]).map(u8::from);
+    let signed = |index: usize| bytes[index] as i8;
+    let mut floats = [0.0; 10];
+    for (value, chunk) in floats.iter_mut().zip(bytes[16..].chunks_exact(4)) {
+        *value = f32::from_le_bytes(chunk.try_into().unwrap());
+        if !value.is_finite() {
+            return Err(format!("Invalid value {} in patch record", value));
+        }
+    }
+    let [cutoff, filter_attack, filter_decay, filter_sustain, filter_release, emphasis, amp_attack, amp_decay, amp_sustain, amp_release] = floats;
+    let patch = Patch {
+        Osc1_Waveform: waveform(0)?,
+        Osc2_Waveform: waveform(1)?,
+        Osc3_Waveform: waveform(2)?,
+        Filter_Mode: FilterMode::try_from(bytes[3])?.into(),
+        Osc1_Coarse: signed(4),
+        Osc1_Fine: signed(5),
+        Osc1_Gain: signed(6),
+        Osc2_Coarse: signed(7),
+        Osc2_Fine: signed(8),
+        Osc2_Gain: signed(9),
+        Osc3_Coarse: signed(10),
+        Osc3_Fine: signed(11),
+        Osc3_Gain: signed(12),
+        Filter_Resonance: bytes[13],
+        Amp_Gain: signed(14),
+        Filter_Cutoff: cutoff,
+        Filter_Attack: filter_attack,
+        Filter_Decay: filter_decay,
+        Filter_Sustain: filter_sustain,
+        Filter_Release: filter_release,
+        Filter_Emphasis: emphasis,
+        Amp_Attack: amp_attack,
+        Amp_Decay: amp_decay,
+        Amp_Sustain: amp_sustain,
+        Amp_Release: amp_release,
+    };
+    let values = PatchUI::from(patch);
+    for desc in PARAMS.iter().filter(|d| d.section != Section_Global) {
+        if let Some(value) = values.get(desc.section, desc.parameter).filter(|v| !(desc.min..=desc.max).contains(v)) {
+            return Err(format!("{} {} is outside {} to {}", desc.full_name(), value, desc.min, desc.max));
+        }
+    }
+    Ok(patch)
+}
+
+/// CRC-32 (IEEE), as used by zlib, so the firmware can check banks with a standard routine.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// Packs patches behind a header:
+///
+/// ```text
+/// offset  size  field
+///      0     4  magic "BSPB"
+///      4     2  protocol version
+///      6     2  patch count
+///      8     4  CRC-32 of the records
+///     12  56*n  patch records
+/// ```
+///
+/// All fields are little-endian.
+pub fn encode_bank(patches: &[Patch]) -> Result<Vec<u8>, String> {
+    let count = u16::try_from(patches.len())
+        .map_err(|_| format!("A binary bank holds up to {} patches, not {}", u16::MAX, patches.len()))?;
+    let records: Vec<u8> = patches.iter().flat_map(encode_patch).collect();
+    let mut bytes = Vec::with_capacity(BANK_HEADER_SIZE + records.len());
+    bytes.extend_from_slice(&BANK_MAGIC);
+    bytes.extend_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&count.to_le_bytes());
+    bytes.extend_from_slice(&crc32(&records).to_le_bytes());
+    bytes.extend_from_slice(&records);
+    Ok(bytes)
+}
+
+pub fn is_bank(bytes: &[u8]) -> bool {
+    bytes.starts_with(&BANK_MAGIC)
+}
+
+pub fn decode_bank(bytes: &[u8]) -> Result<Vec<Patch>, String> {
+    if !is_bank(bytes) || bytes.len() < BANK_HEADER_SIZE {
+        return Err("Not a binary bank: the header is missing".to_string());
+    }
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if version > PROTOCOL_VERSION {
+        return Err(format!("Protocol version {} is newer than this app supports ({})", version, PROTOCOL_VERSION));
+    }
+    let count = u16::from_le_bytes([bytes[6], bytes[7]]) as usize;
+    let checksum = u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]);
+    let records = &bytes[BANK_HEADER_SIZE..];
+    if records.len() != count * PATCH_RECORD_SIZE {
+        return Err(format!("The header says {} patches but the file holds {} bytes of records", count, records.len()));
+    }
+    if crc32(records) != checksum {
+        return Err("The checksum doesn't match: the bank is damaged".to_string());
+    }
+    if count == 0 {
+        return Err("The bank has no patches".to_string());
+    }
+    records.chunks_exact(PATCH_RECORD_SIZE)
+        .enumerate()
+        .map(|(index, record)| decode_patch(record).map_err(|e| format!("Patch {}: {}", index + 1, e)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::sample_patches;
+
+    #[test]
+    fn crc32_matches_the_standard() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn patch_records_round_trip() {
+        for patch in sample_patches() {
+            let bytes = encode_patch(&patch);
+            assert_eq!(bytes[15], 0, "padding is zeroed");
+            assert_eq!(encode_patch(&decode_patch(&bytes).unwrap()), bytes);
+        }
+        let negative = Patch { Osc1_Coarse: -24, Osc2_Fine: -50, Osc3_Gain: i8::MIN, Amp_Gain: -1, ..sample_patches()[2] };
+        let decoded = decode_patch(&encode_patch(&negative)).unwrap();
+        assert_eq!((decoded.Osc1_Coarse, decoded.Osc2_Fine, decoded.Osc3_Gain, decoded.Amp_Gain), (-24, -50, i8::MIN, -1));
+    }
+
+    #[test]
+    fn records_are_little_endian() {
+        let patch = Patch { Filter_Cutoff: 1000.0, ..sample_patches()[0] };
+        assert_eq!(encode_patch(&patch)[16..20], 1000f32.to_le_bytes());
+    }
+
+    #[test]
+    fn bad_records_are_rejected() {
+        let good = encode_patch(&sample_patches()[1]);
+        assert!(decode_patch(&good[..PATCH_RECORD_SIZE - 1]).is_err());
+        let mut waveform = good;
+        waveform[0] = 9;
+        assert!(decode_patch(&waveform).is_err());
+        let mut filter_mode = good;
+        filter_mode[3] = 9;
+        assert!(decode_patch(&filter_mode).is_err());
+        let mut coarse = good;
+        coarse[4] = 100;
+        assert!(decode_patch(&coarse).is_err());
+        let mut cutoff = good;
+        cutoff[16..20].copy_from_slice(&50_000f32.to_le_bytes());
+        assert!(decode_patch(&cutoff).is_err());
+        let mut nan = good;
+        nan[20..24].copy_from_slice(&f32::NAN.to_le_bytes());
+        assert!(decode_patch(&nan).is_err());
+    }
+
+    #[test]
+    fn banks_round_trip() {
+        let patches = sample_patches();
+        let bytes = encode_bank(&patches).unwrap();
+        assert_eq!(bytes.len(), BANK_HEADER_SIZE + patches.len() * PATCH_RECORD_SIZE);
+        assert!(is_bank(&bytes));
+        let decoded = decode_bank(&bytes).unwrap();
+        assert_eq!(decoded.len(), patches.len());
+        for (a, b) in decoded.iter().zip(&patches) {
+            assert_eq!(encode_patch(a), encode_patch(b));
+        }
+    }
+
+    #[test]
+    fn bad_banks_are_rejected() {
+        let bytes = encode_bank(&sample_patches()).unwrap();
+        assert!(decode_bank(&bytes[..BANK_HEADER_SIZE - 1]).is_err());
+        assert!(decode_bank(&bytes[..bytes.len() - 1]).is_err());
+        let mut newer = bytes.clone();
+        newer[4..6].copy_from_slice(&(PROTOCOL_VERSION + 1).to_le_bytes());
+        assert!(decode_bank(&newer).is_err());
+        let mut damaged = bytes.clone();
+        damaged[BANK_HEADER_SIZE + 20] ^= 1;
+        assert!(decode_bank(&damaged).is_err());
+        assert!(decode_bank(&encode_bank(&[]).unwrap()).is_err());
+    }
+
+    #[test]
+    fn banks_too_big_for_the_header_are_refused() {
+        let patches = vec![sample_patches()[0]; u16::MAX as usize + 1];
+        assert!(encode_bank(&patches).is_err());
+        assert!(encode_bank(&patches[1..]).is_ok());
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use midir::MidiOutputConnection;
+
+pub const PULSES_PER_BEAT: u32 = 24;
+/// How close to a deadline `wait_until` stops sleeping and starts spinning.
+const SPIN_THRESHOLD: Duration = Duration::from_millis(2);
+const IDLE_POLL: Duration = Duration::from_millis(10);
+/// Number of MIDI clock pulses averaged when estimating the incoming tempo.
+const TEMPO_WINDOW: usize = PULSES_PER_BEAT as usize;
+/// Longest a player sleeps before looking at the clock again, so it follows tempo changes
+/// and waits out pulses that are late.
+pub const MAX_WAIT: Duration = Duration::from_millis(5);
+/// Beats per bar, for lining up with the other peers in a Link session.
+#[cfg(feature = "link")]
+const LINK_QUANTUM: f64 = 4.0;
+
+const MIDI_CLOCK: u8 = 0xF8;
+const MIDI_START: u8 = 0xFA;
+const MIDI_CONTINUE: u8 = 0xFB;
+const MIDI_STOP: u8 = 0xFC;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClockSource {
+    Internal,
+    MidiClock,
+    #[cfg(feature = "link")]
+    Link,
+}
+
+impl ClockSource {
+    pub const ALL: &'static [ClockSource] = &[
+        ClockSource::Internal,
+        ClockSource::MidiClock,
+        #[cfg(feature = "link")]
+        ClockSource::Link,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClockSource::Internal => "Internal",
+            ClockSource::MidiClock => "MIDI clock",
+            #[cfg(feature = "link")]
+            ClockSource::Link => "Ableton Link",
+        }
+    }
+}
+
+/// Tempo and transport shared by everything in the app that plays in time.
+pub struct Clock {
+    pub tempo: f32,
+    pub source: ClockSource,
+    /// Transport state. Started and stopped by the sequencer when the clock is internal,
+    /// and by the external clock otherwise.
+    pub running: bool,
+    /// Send MIDI clock and start/stop to the MIDI output while this app is the master.
+    pub send_midi_clock: bool,
+    /// Beats counted since the transport started, as of `updated`. Set by each MIDI clock
+    /// pulse or by the Link session when following them.
+    position: f64,
+    updated: Instant,
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Clock {
+            tempo: 120.0,
+            source: ClockSource::Internal,
+            running: false,
+            send_midi_clock: false,
+            position: 0.0,
+            updated: Instant::now(),
+        }
+    }
+}
+
+impl Clock {
+    pub fn beat(&self) -> Duration {
+        Duration::from_secs_f64(60.0 / self.tempo as f64)
+    }
+
+    /// Whether the tempo is set by something outside the app.
+    pub fn is_external(&self) -> bool {
+        self.source != ClockSource::Internal
+    }
+
+    /// Starts or stops the transport, counting beats from zero on starting.
+    pub fn set_running(&mut self, running: bool) {
+        if running && !self.running {
+            self.set_position(0.0, Instant::now());
+        }
+        self.running = running;
+    }
+
+    fn set_position(&mut self, beat: f64, at: Instant) {
+        self.position = beat;
+        self.updated = at;
+    }
+
+    /// The beat at `now`. Between updates it moves on at the tempo, but while following MIDI
+    /// clock it never gets more than a pulse ahead of the last pulse received, so steps are
+    /// driven by the pulses.
+    pub fn beat_at(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64() * self.tempo as f64 / 60.0;
+        match self.source {
+            ClockSource::MidiClock => self.position + elapsed.min(1.0 / PULSES_PER_BEAT as f64),
+            _ => self.position + elapsed,
+        }
+    }
+
+    /// The beat now, counted up to now so that tempo changes only affect what comes after.
+    pub fn advance(&mut self) -> f64 {
+        let now = Instant::now();
+        let beat = self.beat_at(now);
+        if self.source != ClockSource::MidiClock {
+            self.set_position(beat, now);
+        }
+        beat
+    }
+
+    /// When `beat` is expected, at the current tempo.
+    pub fn time_of(&self, beat: f64) -> Instant {
+        let beats = (beat - self.position).max(0.0);
+        self.updated + Duration::from_secs_f64(beats * 60.0 / self.tempo as f64)
+    }
+}
+
+/// Sleeps until shortly before `deadline` and spins for the rest, for timing that is
+/// tighter than the OS scheduler alone gives.
+pub fn wait_until(deadline: Instant) {
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return;
+        }
+        let remaining = deadline - now;
+        if remaining > SPIN_THRESHOLD {
+            thread::sleep(remaining - SPIN_THRESHOLD);
+        } else {
+            thread::yield_now();
+        }
+    }
+}
+
+/// Follows incoming MIDI clock, counting beats from the pulses and estimating the tempo from
+/// their spacing.
+#[derive(Default)]
+pub struct MidiClockFollower {
+    last_pulse: Option<u64>,
+    intervals: VecDeque<u64>,
+    /// Pulses since the last start; the first pulse after a start is beat zero.
+    pulses: u64,
+}
+
+impl MidiClockFollower {
+    /// Handles a realtime message, timestamped in microseconds. Returns true if it was one.
+    pub fn handle(&mut self, status: u8, timestamp: u64, clock: &Mutex<Clock>) -> bool {
+        let mut clock = clock.lock().unwrap();
+        if clock.source != ClockSource::MidiClock {
+            self.last_pulse = None;
+            self.intervals.clear();
+            return matches!(status, MIDI_CLOCK | MIDI_START | MIDI_CONTINUE | MIDI_STOP);
+        }
+        match status {
+            MIDI_CLOCK => {
+                if let Some(last) = self.last_pulse {
+                    self.intervals.push_back(timestamp.saturating_sub(last));
+                    if self.intervals.len() > TEMPO_WINDOW {
+                        self.intervals.pop_front();
+                    }
+                    let average = self.intervals.iter().sum::<u64>() as f64 / self.intervals.len() as f64;
+                    if average > 0.0 {
+                        let tempo = 60_000_000.0 / (average * PULSES_PER_BEAT as f64);
+                        clock.tempo = (tempo as f32).clamp(20.0, 400.0);
+                    }
+                }
+                self.last_pulse = Some(timestamp);
+                clock.set_position(self.pulses as f64 / PULSES_PER_BEAT as f64, Instant::now());
+                self.pulses += 1;
+            }
+            MIDI_START => {
+                self.pulses = 0;
+                clock.set_running(true);
+            }
+            MIDI_CONTINUE => clock.running = true,
+            MIDI_STOP => clock.running = false,
+            _ => return false,
+        }
+        true
+    }
+}
+
+/// Sends MIDI clock and start/stop to the MIDI output while this app is the clock master.
+pub fn run_clock_output(clock: Arc<Mutex<Clock>>, output: Arc<Mutex<Option<MidiOutputConnection>>>) {
+    let mut next_pulse = Instant::now();
+    let mut was_running = false;
+    loop {
+        if Arc::strong_count(&output) == 1 {
+            return;
+        }
+        let (pulse, running, master) = {
+            let clock = clock.lock().unwrap();
+            let master = clock.send_midi_clock && clock.source != ClockSource::MidiClock;
+            (clock.beat() / PULSES_PER_BEAT, clock.running, master)
+        };
+        let sent = {
+            let mut guard = output.lock().unwrap();
+            match guard.as_mut().filter(|_| master) {
+                Some(connection) => {
+                    if running != was_running {
+                        let _ = connection.send(&[if running { MIDI_START } else { MIDI_STOP }]);
+                        was_running = running;
+                    }
+                    let _ = connection.send(&[MIDI_CLOCK]);
+                    true
+                }
+                None => false,
+            }
+        };
+        if !sent {
+            was_running = false;
+            thread::sleep(IDLE_POLL);
+            next_pulse = Instant::now();
+            continue;
+        }
+
+        next_pulse += pulse;
+        let now = Instant::now();
+        if next_pulse < now {
+            next_pulse = now + pulse;
+        }
+        wait_until(next_pulse);
+    }
+}
+
+/// Joins an Ableton Link session while it is the clock source, keeping the tempo, beat and
+/// transport in step with the other peers in both directions.
+#[cfg(feature = "link")]
+pub fn run_link(clock: Arc<Mutex<Clock>>) {
+    use rusty_link::{AblLink, SessionState};
+
+    let link = AblLink::new(120.0);
+    link.enable_start_stop_sync(true);
+    let mut session = SessionState::new();
+    // The last values exchanged with the session, to tell local changes from remote ones.
+    let mut tempo = 0.0;
+    let mut running = false;
+    let mut joined = false;
+    loop {
+        thread::sleep(IDLE_POLL);
+        if Arc::strong_count(&clock) == 1 {
+            return;
+        }
+        let mut clock = clock.lock().unwrap();
+        let active = clock.source == ClockSource::Link;
+        if link.is_enabled() != active {
+            link.enable(active);
+        }
+        if !active {
+            joined = false;
+            continue;
+        }
+
+        link.capture_app_session_state(&mut session);
+        let now = link.clock_micros();
+        if !joined {
+            // Take the session's tempo on joining rather than imposing ours on the other peers.
+            clock.tempo = session.tempo() as f32;
+            clock.running = session.is_playing();
+            tempo = clock.tempo;
+            running = clock.running;
+            joined = true;
+            continue;
+        }
+        let mut changed = false;
+        if clock.tempo != tempo {
+            session.set_tempo(clock.tempo as f64, now);
+            changed = true;
+        }
+        if clock.running != running {
+            if clock.running {
+                // Starting puts the downbeat here, as the beat count starts from zero.
+                session.set_is_playing_and_request_beat_at_time(true, now as u64, 0.0, LINK_QUANTUM);
+            } else {
+                session.set_is_playing(false, now as u64);
+            }
+            changed = true;
+        }
+        if changed {
+            link.commit_app_session_state(&session);
+        }
+        clock.tempo = session.tempo() as f32;
+        clock.running = session.is_playing();
+        clock.set_position(session.beat_at_time(now, LINK_QUANTUM), Instant::now());
+        tempo = clock.tempo;
+        running = clock.running;
+    }
+}
+
+pub fn draw_clock_menu(clock: &Mutex<Clock>, ui: &mut egui::Ui) {
+    let mut clock = clock.lock().unwrap();
+    for source in ClockSource::ALL {
+        ui.radio_value(&mut clock.source, *source, source.name());
+    }
+    ui.separator();
+    ui.checkbox(&mut clock.send_midi_clock, "Send MIDI clock");
+    ui.label(format!("{:.1} bpm{}", clock.tempo, if clock.running { ", running" } else { "" }));
+}
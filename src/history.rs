@@ -0,0 +1,191 @@
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+use egui::{Key, KeyboardShortcut, Modifiers};
+use crate::app::{Message, PatchUI};
+use crate::params::PARAMS;
+
+/// Oldest steps are forgotten beyond this.
+const MAX_STEPS: usize = 200;
+/// Changes from MIDI controllers to the same parameters this close together are one step, as a
+/// knob turned on a controller arrives a value at a time.
+const MERGE_WINDOW: Duration = Duration::from_secs(1);
+
+const UNDO: KeyboardShortcut = KeyboardShortcut::new(Modifiers::COMMAND, Key::Z);
+const REDO: KeyboardShortcut = KeyboardShortcut::new(Modifiers::COMMAND.plus(Modifiers::SHIFT), Key::Z);
+
+/// One parameter's change, by its index in `PARAMS`.
+#[derive(Clone, Copy)]
+struct Change {
+    index: usize,
+    before: f32,
+    after: f32,
+}
+
+struct Step {
+    changes: Vec<Change>,
+    time: Instant,
+    /// Whether the changes came from MIDI controllers, so later ones can be merged into it.
+    controlled: bool,
+}
+
+impl Step {
+    fn describe(&self) -> String {
+        let first = &self.changes[0];
+        let desc = &PARAMS[first.index];
+        match self.changes.len() {
+            1 => format!("{} {}", desc.full_name(), desc.format(first.after)),
+            n => format!("{} and {} more", desc.full_name(), n - 1),
+        }
+    }
+
+    /// Sets each parameter to its value before or after the step, sending it to the synth.
+    fn apply(&self, patch: &mut PatchUI, after: bool, sender: &Sender<Message>) {
+        for change in &self.changes {
+            let desc = &PARAMS[change.index];
+            let value = if after { change.after } else { change.before };
+            if let Some(msg) = patch.set(desc.section, desc.parameter, value) {
+                sender.send(msg).unwrap();
+            }
+        }
+    }
+}
+
+fn values(patch: &PatchUI) -> Vec<Option<f32>> {
+    PARAMS.iter().map(|desc| patch.get(desc.section, desc.parameter)).collect()
+}
+
+/// Undo and redo for the patch. Rather than each control reporting its edits, the patch is
+/// compared with how it was after the last step once no control is being dragged, so a whole
+/// drag is one step and edits from anywhere (MIDI, the randomizer, loading, recalling a bank
+/// slot) are covered. Patches that are only being listened to, such as library previews, the
+/// stored patch while comparing and morphs, are passed to `reset` instead.
+pub struct History {
+    pub open: bool,
+    baseline: Vec<Option<f32>>,
+    undo: Vec<Step>,
+    redo: Vec<Step>,
+}
+
+impl History {
+    pub fn new(patch: &PatchUI) -> Self {
+        History { open: false, baseline: values(patch), undo: Vec::new(), redo: Vec::new() }
+    }
+
+    /// Takes the patch as it is without recording a step, for changes that aren't edits, such
+    /// as the synth reporting its patch.
+    pub fn reset(&mut self, patch: &PatchUI) {
+        self.baseline = values(patch);
+    }
+
+    /// Records the changes since the last step, unless a control is still being dragged.
+    /// `controlled` is whether MIDI controllers made them.
+    pub fn record(&mut self, patch: &PatchUI, controlled: bool, ctx: &egui::Context) {
+        if ctx.dragged_id().is_some() || ctx.input(|i| i.pointer.any_down()) {
+            return;
+        }
+        let current = values(patch);
+        let changes: Vec<Change> = self.baseline.iter().zip(&current).enumerate()
+            .filter_map(|(index, (before, after))| match (before, after) {
+                (Some(before), Some(after)) if before != after => Some(Change { index, before: *before, after: *after }),
+                _ => None,
+            })
+            .collect();
+        self.baseline = current;
+        if changes.is_empty() {
+            return;
+        }
+        self.redo.clear();
+        let now = Instant::now();
+        if let Some(last) = self.undo.last_mut() {
+            let same_params = last.changes.len() == changes.len()
+                && last.changes.iter().zip(&changes).all(|(a, b)| a.index == b.index);
+            if controlled && last.controlled && same_params && now.duration_since(last.time) < MERGE_WINDOW {
+                for (merged, change) in last.changes.iter_mut().zip(&changes) {
+                    merged.after = change.after;
+                }
+                last.time = now;
+                return;
+            }
+        }
+        self.undo.push(Step { changes, time: now, controlled });
+        if self.undo.len() > MAX_STEPS {
+            self.undo.remove(0);
+        }
+    }
+
+    pub fn undo(&mut self, patch: &mut PatchUI, sender: &Sender<Message>) {
+        if let Some(step) = self.undo.pop() {
+            step.apply(patch, false, sender);
+            self.redo.push(step);
+            self.reset(patch);
+        }
+    }
+
+    pub fn redo(&mut self, patch: &mut PatchUI, sender: &Sender<Message>) {
+        if let Some(step) = self.redo.pop() {
+            step.apply(patch, true, sender);
+            self.undo.push(step);
+            self.reset(patch);
+        }
+    }
+}
+
+/// Undoes on Ctrl+Z and redoes on Ctrl+Shift+Z or Ctrl+Y, leaving them to text fields that
+/// have focus.
+pub fn handle_history_keys(history: &mut History, patch: &mut PatchUI, sender: &Sender<Message>, ctx: &egui::Context) {
+    if ctx.wants_keyboard_input() {
+        return;
+    }
+    // Ctrl+Z would also match Ctrl+Shift+Z, so redo is checked first.
+    let redo = ctx.input_mut(|i| i.consume_shortcut(&REDO) || i.consume_key(Modifiers::COMMAND, Key::Y));
+    if redo {
+        history.redo(patch, sender);
+    } else if ctx.input_mut(|i| i.consume_shortcut(&UNDO)) {
+        history.undo(patch, sender);
+    }
+}
+
+/// Lists the steps, oldest first, with those that can be redone greyed out. Clicking a step
+/// undoes or redoes up to it.
+pub fn draw_history(history: &mut History, patch: &mut PatchUI, sender: &Sender<Message>, ctx: &egui::Context) {
+    let mut open = history.open;
+    egui::Window::new("History").open(&mut open).show(ctx, |ui| {
+        ui.horizontal(|ui| {
+            if ui.add_enabled(!history.undo.is_empty(), egui::Button::new("Undo")).clicked() {
+                history.undo(patch, sender);
+            }
+            if ui.add_enabled(!history.redo.is_empty(), egui::Button::new("Redo")).clicked() {
+                history.redo(patch, sender);
+            }
+        });
+        ui.separator();
+        // Some(n) goes to the state with n steps done.
+        let mut target = None;
+        egui::ScrollArea::vertical().max_height(300.0).stick_to_bottom(true).show(ui, |ui| {
+            if ui.selectable_label(history.undo.is_empty(), "Start").clicked() {
+                target = Some(0);
+            }
+            let done = history.undo.len();
+            for (index, step) in history.undo.iter().enumerate() {
+                if ui.selectable_label(index + 1 == done, step.describe()).clicked() {
+                    target = Some(index + 1);
+                }
+            }
+            for (index, step) in history.redo.iter().rev().enumerate() {
+                let label = egui::RichText::new(step.describe()).weak();
+                if ui.selectable_label(false, label).clicked() {
+                    target = Some(done + index + 1);
+                }
+            }
+        });
+        if let Some(target) = target {
+            while history.undo.len() > target {
+                history.undo(patch, sender);
+            }
+            while history.undo.len() < target && !history.redo.is_empty() {
+                history.redo(patch, sender);
+            }
+        }
+    });
+    history.open = open;
+}
@@ -0,0 +1,256 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::mpsc::Sender;
+use std::time::Instant;
+use egui::{Color32, Event, Key, Pos2, Rect, Rounding, Sense, Stroke, Ui, Vec2};
+use crate::app::{Message, Note};
+
+const OCTAVES: u8 = 2;
+const WHITE_KEY_WIDTH: f32 = 24.0;
+const WHITE_KEY_HEIGHT: f32 = 90.0;
+const BLACK_KEY_WIDTH: f32 = 15.0;
+const BLACK_KEY_HEIGHT: f32 = 55.0;
+pub const MAX_OCTAVE: u8 = 7;
+const VELOCITY_STEP: u8 = 16;
+
+/// Tracker-style layout: the bottom two rows play from the leftmost C, the top two rows an octave up.
+const QWERTY_KEYS: [(Key, u8); 34] = [
+    (Key::Z, 0), (Key::S, 1), (Key::X, 2), (Key::D, 3), (Key::C, 4), (Key::V, 5),
+    (Key::G, 6), (Key::B, 7), (Key::H, 8), (Key::N, 9), (Key::J, 10), (Key::M, 11),
+    (Key::Comma, 12), (Key::L, 13), (Key::Period, 14), (Key::Semicolon, 15), (Key::Slash, 16),
+    (Key::Q, 12), (Key::Num2, 13), (Key::W, 14), (Key::Num3, 15), (Key::E, 16), (Key::R, 17),
+    (Key::Num5, 18), (Key::T, 19), (Key::Num6, 20), (Key::Y, 21), (Key::Num7, 22), (Key::U, 23),
+    (Key::I, 24), (Key::Num9, 25), (Key::O, 26), (Key::Num0, 27), (Key::P, 28),
+];
+
+/// Semitone offsets of the white keys within an octave.
+const WHITE_KEYS: [u8; 7] = [0, 2, 4, 5, 7, 9, 11];
+/// Semitone offsets of the black keys, paired with the white key they sit to the right of.
+const BLACK_KEYS: [(u8, usize); 5] = [(1, 0), (3, 1), (6, 3), (8, 4), (10, 5)];
+
+pub struct KeyboardState {
+    /// Octave of the leftmost C, so that octave 2 starts at MIDI note 36 (C2).
+    pub octave: u8,
+    /// When latched, clicking a key toggles it rather than holding it.
+    pub latch: bool,
+    /// Velocity used for notes played from the computer keyboard.
+    pub velocity: u8,
+    held: BTreeSet<Note>,
+    pointer_note: Option<Note>,
+    /// Notes started from the computer keyboard, so the key-up releases the note that was
+    /// actually played even if the octave changed in between.
+    qwerty_notes: HashMap<Key, Note>,
+}
+
+impl Default for KeyboardState {
+    fn default() -> Self {
+        KeyboardState {
+            octave: 2,
+            latch: false,
+            velocity: 100,
+            held: BTreeSet::new(),
+            pointer_note: None,
+            qwerty_notes: HashMap::new(),
+        }
+    }
+}
+
+impl KeyboardState {
+    pub fn base_note(&self) -> Note {
+        12 * (self.octave + 1)
+    }
+
+    pub fn is_held(&self, note: Note) -> bool {
+        self.held.contains(&note)
+    }
+
+    pub fn note_on(&mut self, note: Note, velocity: u8, sender: &Sender<Message>) {
+        if self.held.insert(note) {
+            sender.send(Message::NoteOn(note, velocity.max(1), Instant::now())).unwrap();
+        }
+    }
+
+    pub fn note_off(&mut self, note: Note, sender: &Sender<Message>) {
+        if self.held.remove(&note) {
+            sender.send(Message::NoteOff(note, Instant::now())).unwrap();
+        }
+    }
+
+    pub fn release_all(&mut self, sender: &Sender<Message>) {
+        for note in std::mem::take(&mut self.held) {
+            sender.send(Message::NoteOff(note, Instant::now())).unwrap();
+        }
+        self.pointer_note = None;
+        self.qwerty_notes.clear();
+    }
+
+    fn shift_octave(&mut self, up: bool, sender: &Sender<Message>) {
+        if up && self.octave < MAX_OCTAVE {
+            self.release_all(sender);
+            self.octave += 1;
+        } else if !up && self.octave > 0 {
+            self.release_all(sender);
+            self.octave -= 1;
+        }
+    }
+
+    fn press(&mut self, note: Note, velocity: u8, sender: &Sender<Message>) {
+        if self.latch && self.is_held(note) {
+            self.note_off(note, sender);
+        } else {
+            self.note_on(note, velocity, sender);
+        }
+    }
+}
+
+fn white_key_rect(keyboard: Rect, index: usize) -> Rect {
+    Rect::from_min_size(
+        keyboard.min + Vec2::new(index as f32 * WHITE_KEY_WIDTH, 0.0),
+        Vec2::new(WHITE_KEY_WIDTH, WHITE_KEY_HEIGHT),
+    )
+}
+
+fn black_key_rect(keyboard: Rect, white_index: usize) -> Rect {
+    let x = (white_index + 1) as f32 * WHITE_KEY_WIDTH - BLACK_KEY_WIDTH / 2.0;
+    Rect::from_min_size(
+        keyboard.min + Vec2::new(x, 0.0),
+        Vec2::new(BLACK_KEY_WIDTH, BLACK_KEY_HEIGHT),
+    )
+}
+
+/// Finds the key under `pos` as a semitone offset from the leftmost C, along with
+/// a velocity taken from how far down the key was struck.
+fn key_at(keyboard: Rect, pos: Pos2) -> Option<(u8, u8)> {
+    if !keyboard.contains(pos) {
+        return None;
+    }
+    let velocity = |key: Rect| {
+        let depth = ((pos.y - key.top()) / key.height()).clamp(0.0, 1.0);
+        1 + (depth * 126.0).round() as u8
+    };
+    for octave in 0..OCTAVES {
+        for (semitone, white_index) in BLACK_KEYS {
+            let key = black_key_rect(keyboard, octave as usize * 7 + white_index);
+            if key.contains(pos) {
+                return Some((octave * 12 + semitone, velocity(key)));
+            }
+        }
+    }
+    let white_index = ((pos.x - keyboard.left()) / WHITE_KEY_WIDTH) as usize;
+    let white_index = white_index.min(OCTAVES as usize * 7 - 1);
+    let octave = (white_index / 7) as u8;
+    let key = white_key_rect(keyboard, white_index);
+    Some((octave * 12 + WHITE_KEYS[white_index % 7], velocity(key)))
+}
+
+pub fn draw_keyboard(state: &mut KeyboardState, sender: &Sender<Message>, ui: &mut Ui) {
+    ui.horizontal(|ui| {
+        ui.label("Octave");
+        if ui.button("-").clicked() {
+            state.shift_octave(false, sender);
+        }
+        ui.label(format!("{}", state.octave));
+        if ui.button("+").clicked() {
+            state.shift_octave(true, sender);
+        }
+        if ui.toggle_value(&mut state.latch, "Latch").changed() && !state.latch {
+            state.release_all(sender);
+        }
+        ui.label("Vel");
+        ui.add(egui::DragValue::new(&mut state.velocity).range(1..=127));
+    })
+    .response
+    .on_hover_text("Keys Z-/ and Q-P play notes, [ and ] shift octave, - and = change velocity");
+
+    let white_keys = OCTAVES as usize * 7;
+    let size = Vec2::new(white_keys as f32 * WHITE_KEY_WIDTH, WHITE_KEY_HEIGHT);
+    let (rect, response) = ui.allocate_exact_size(size, Sense::click_and_drag());
+    let base = state.base_note();
+
+    // Follow the pointer while it is held down, so dragging across the keys plays a glissando.
+    let pointer = if response.is_pointer_button_down_on() {
+        ui.input(|i| i.pointer.interact_pos())
+            .and_then(|pos| key_at(rect, pos))
+            .map(|(offset, velocity)| (base + offset, velocity))
+    } else {
+        None
+    };
+    let pointer_note = pointer.map(|(note, _)| note);
+    if pointer_note != state.pointer_note {
+        if let Some(previous) = state.pointer_note {
+            if !state.latch {
+                state.note_off(previous, sender);
+            }
+        }
+        if let Some((note, velocity)) = pointer {
+            state.press(note, velocity, sender);
+        }
+        state.pointer_note = pointer_note;
+    }
+
+    let painter = ui.painter_at(rect);
+    let outline = Stroke::new(1.0, Color32::DARK_GRAY);
+    let highlight = ui.visuals().selection.bg_fill;
+    for index in 0..white_keys {
+        let note = base + (index / 7) as u8 * 12 + WHITE_KEYS[index % 7];
+        let fill = if state.is_held(note) { highlight } else { Color32::WHITE };
+        let key = white_key_rect(rect, index);
+        painter.rect(key, Rounding::same(2.0), fill, outline);
+    }
+    for octave in 0..OCTAVES {
+        for (semitone, white_index) in BLACK_KEYS {
+            let note = base + octave * 12 + semitone;
+            let fill = if state.is_held(note) { highlight } else { Color32::BLACK };
+            let key = black_key_rect(rect, octave as usize * 7 + white_index);
+            painter.rect(key, Rounding::same(2.0), fill, outline);
+        }
+    }
+}
+
+/// Plays notes from the computer keyboard using the tracker layout in `QWERTY_KEYS`.
+///
+/// Nothing is played while a text field has focus or while a command modifier is held, so
+/// typing and shortcuts keep working. Key-ups are always honoured so no note is left hanging.
+pub fn handle_computer_keyboard(state: &mut KeyboardState, ctx: &egui::Context, sender: &Sender<Message>) {
+    let typing = ctx.wants_keyboard_input();
+    let events = ctx.input(|i| i.events.clone());
+    for event in events {
+        match event {
+            Event::Key { key, physical_key, pressed, repeat, modifiers } => {
+                // Use the physical position where the platform reports it, so the layout
+                // stays a piano on AZERTY and Dvorak keyboards.
+                let key = physical_key.unwrap_or(key);
+                if !pressed {
+                    if let Some(note) = state.qwerty_notes.remove(&key) {
+                        state.note_off(note, sender);
+                    }
+                    continue;
+                }
+                if repeat || typing || modifiers.command || modifiers.ctrl || modifiers.alt {
+                    continue;
+                }
+                match key {
+                    Key::OpenBracket => state.shift_octave(false, sender),
+                    Key::CloseBracket => state.shift_octave(true, sender),
+                    Key::Minus => state.velocity = state.velocity.saturating_sub(VELOCITY_STEP).max(1),
+                    Key::Equals => state.velocity = state.velocity.saturating_add(VELOCITY_STEP).min(127),
+                    _ => {
+                        if let Some((_, offset)) = QWERTY_KEYS.iter().find(|(k, _)| *k == key) {
+                            let note = state.base_note() + offset;
+                            if !state.is_held(note) {
+                                state.qwerty_notes.insert(key, note);
+                                state.note_on(note, state.velocity, sender);
+                            }
+                        }
+                    }
+                }
+            }
+            Event::WindowFocused(false) => {
+                // Key-ups are lost once the window is in the background.
+                for (_, note) in std::mem::take(&mut state.qwerty_notes) {
+                    state.note_off(note, sender);
+                }
+            }
+            _ => {}
+        }
+    }
+}
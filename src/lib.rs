@@ -5,6 +5,25 @@
 
 mod app;
 mod server;
+mod keyboard;
+mod midi;
+mod sequencer;
+mod arpeggiator;
+mod clock;
+mod midi_file;
+mod recorder;
+mod params;
+mod synth;
+mod patch;
+mod bank;
+mod binary;
+mod sysex;
+mod library;
+mod randomize;
+mod morph;
+mod history;
+#[cfg(test)]
+mod test_util;
 pub use app::BassSynthUI;
 mod bindings;
 
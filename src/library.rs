@@ -0,0 +1,303 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use egui_file_dialog::FileDialog;
+use crate::app::{Message, PatchUI};
+use crate::bindings::Patch;
+use crate::patch::{is_patch_text, patch_from_bytes, save_patch, PatchFile, PatchInfo, MAX_RATING};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SortKey {
+    Name,
+    Author,
+    Rating,
+}
+
+pub struct LibraryEntry {
+    pub path: PathBuf,
+    pub file: PatchFile,
+    /// Only text files can hold the details, so others can't have them edited.
+    pub editable: bool,
+}
+
+impl LibraryEntry {
+    /// Whether every word of the search appears in the name, author, tags or notes.
+    fn matches(&self, search: &str) -> bool {
+        let info = &self.file.info;
+        let haystack = format!("{} {} {} {}", self.file.name, info.author, info.tags.join(" "), info.notes).to_lowercase();
+        search.to_lowercase().split_whitespace().all(|word| haystack.contains(word))
+    }
+}
+
+/// The patch files in a directory, with their details.
+pub struct Library {
+    pub dir: Option<PathBuf>,
+    pub open: bool,
+    /// Whether the window was shown last frame, as it can also be closed from the menu bar.
+    shown: bool,
+    entries: Vec<LibraryEntry>,
+    /// Files in the directory that couldn't be read as patches.
+    skipped: usize,
+    search: String,
+    sort: SortKey,
+    selected: Option<usize>,
+    /// Tags of the selected entry as typed, applied when they lose focus.
+    tags: String,
+    /// Whether the details of the selected entry have been edited since they were saved.
+    unsaved: bool,
+    /// The patch being edited before previewing started, restored on Cancel.
+    original: Option<PatchFile>,
+    dialog: FileDialog,
+    error: Option<String>,
+}
+
+impl Default for Library {
+    fn default() -> Self {
+        Library {
+            dir: None,
+            open: false,
+            shown: false,
+            entries: Vec::new(),
+            skipped: 0,
+            search: String::new(),
+            sort: SortKey::Name,
+            selected: None,
+            tags: String::new(),
+            unsaved: false,
+            original: None,
+            dialog: FileDialog::new(),
+            error: None,
+        }
+    }
+}
+
+impl Library {
+    /// Indexes every patch file directly inside `dir`.
+    pub fn scan(&mut self, dir: &Path) {
+        self.commit();
+        self.dir = Some(dir.to_path_buf());
+        self.entries.clear();
+        self.skipped = 0;
+        self.selected = None;
+        let read_dir = match std::fs::read_dir(dir) {
+            Ok(read_dir) => read_dir,
+            Err(e) => {
+                self.error = Some(format!("Failed to read {}: {}", dir.display(), e));
+                return;
+            }
+        };
+        self.error = None;
+        for path in read_dir.filter_map(|entry| entry.ok()).map(|entry| entry.path()).filter(|path| path.is_file()) {
+            let loaded = std::fs::read(&path).map_err(|e| e.to_string()).and_then(|bytes| {
+                let editable = is_patch_text(&bytes);
+                patch_from_bytes(&path, bytes).map(|file| (file, editable))
+            });
+            match loaded {
+                Ok((file, editable)) => self.entries.push(LibraryEntry { path, file, editable }),
+                Err(_) => self.skipped += 1,
+            }
+        }
+    }
+
+    /// Indices of the entries that match the search, in sort order.
+    fn visible(&self) -> Vec<usize> {
+        let mut visible: Vec<usize> = (0..self.entries.len()).filter(|i| self.entries[*i].matches(&self.search)).collect();
+        let name = |i: &usize| self.entries[*i].file.name.to_lowercase();
+        match self.sort {
+            SortKey::Name => visible.sort_by_key(name),
+            SortKey::Author => visible.sort_by_key(|i| (self.entries[*i].file.info.author.to_lowercase(), name(i))),
+            SortKey::Rating => visible.sort_by_key(|i| (std::cmp::Reverse(self.entries[*i].file.info.rating), name(i))),
+        }
+        visible
+    }
+
+    /// Applies the typed tags to the selected entry and saves its details if anything changed.
+    fn commit(&mut self) {
+        let Some(entry) = self.selected.map(|index| &mut self.entries[index]).filter(|entry| entry.editable) else {
+            return;
+        };
+        let tags = std::mem::take(&mut entry.file.info.tags);
+        entry.file.info.set_tags(&self.tags);
+        self.tags = entry.file.info.tags.join(", ");
+        if self.unsaved || entry.file.info.tags != tags {
+            self.error = save_patch(&entry.path, &entry.file).err();
+            self.unsaved = false;
+        }
+    }
+
+    /// Sends an entry to the synth, remembering the patch being edited the first time.
+    fn preview(&mut self, index: usize, patch: &mut PatchUI, name: &str, info: &PatchInfo, sender: &Sender<Message>) {
+        // The details of the entry being left only get saved when they lose focus, which won't
+        // happen once it's no longer shown.
+        self.commit();
+        if self.original.is_none() {
+            self.original = Some(PatchFile { name: name.to_string(), info: info.clone(), patch: Patch::from(&*patch) });
+        }
+        self.selected = Some(index);
+        self.tags = self.entries[index].file.info.tags.join(", ");
+        for msg in patch.load(self.entries[index].file.patch) {
+            sender.send(msg).unwrap();
+        }
+    }
+
+    /// Makes the previewed entry the patch being edited.
+    fn keep(&mut self, name: &mut String, info: &mut PatchInfo) {
+        self.commit();
+        if let (Some(_), Some(index)) = (self.original.take(), self.selected) {
+            name.clone_from(&self.entries[index].file.name);
+            info.clone_from(&self.entries[index].file.info);
+        }
+    }
+
+    /// Goes back to the patch that was being edited before previewing, returning whether there
+    /// was one.
+    fn cancel(&mut self, patch: &mut PatchUI, name: &mut String, info: &mut PatchInfo, sender: &Sender<Message>) -> bool {
+        let Some(original) = self.original.take() else {
+            return false;
+        };
+        for msg in patch.load(original.patch) {
+            sender.send(msg).unwrap();
+        }
+        *name = original.name;
+        *info = original.info;
+        true
+    }
+}
+
+fn sort_button(library: &mut Library, key: SortKey, label: &str, ui: &mut egui::Ui) {
+    let text = if library.sort == key { format!("{} ⏷", label) } else { label.to_string() };
+    if ui.add(egui::Button::new(egui::RichText::new(text).strong()).frame(false)).clicked() {
+        library.sort = key;
+    }
+}
+
+fn rating_text(rating: u8) -> String {
+    "★".repeat(rating as usize)
+}
+
+fn draw_details(library: &mut Library, index: usize, ui: &mut egui::Ui) {
+    let entry = &mut library.entries[index];
+    ui.label(entry.path.display().to_string());
+    // Text is saved when it loses focus rather than on every keystroke.
+    let mut commit = false;
+    ui.add_enabled_ui(entry.editable, |ui| {
+        egui::Grid::new("Library details").num_columns(2).show(ui, |ui| {
+            let info = &mut entry.file.info;
+            ui.label("Author");
+            let author = ui.text_edit_singleline(&mut info.author);
+            library.unsaved |= author.changed();
+            commit |= author.lost_focus();
+            ui.end_row();
+            ui.label("Tags");
+            let tags = ui.text_edit_singleline(&mut library.tags).on_hover_text("Separated by commas, e.g. acid, sub, pluck");
+            commit |= tags.lost_focus();
+            ui.end_row();
+            ui.label("Rating");
+            ui.horizontal(|ui| {
+                for rating in 1..=MAX_RATING {
+                    let star = if rating <= info.rating { "★" } else { "☆" };
+                    if ui.add(egui::Button::new(star).frame(false)).clicked() {
+                        // Clicking the current rating clears it.
+                        info.rating = if info.rating == rating { 0 } else { rating };
+                        library.unsaved = true;
+                        commit = true;
+                    }
+                }
+            });
+            ui.end_row();
+            ui.label("Notes");
+            let notes = ui.text_edit_multiline(&mut info.notes);
+            library.unsaved |= notes.changed();
+            commit |= notes.lost_focus();
+            ui.end_row();
+        });
+    });
+    if !entry.editable {
+        ui.label("Only patch text files can hold details.");
+    }
+    if commit {
+        library.commit();
+    }
+}
+
+/// Shows the library: a searchable list of the patch files in a directory. Selecting one sends
+/// it to the synth to listen to; Keep makes it the patch being edited and Cancel goes back.
+/// Returns whether the patch was replaced by previewing or going back.
+pub fn draw_library(library: &mut Library, patch: &mut PatchUI, name: &mut String, info: &mut PatchInfo, sender: &Sender<Message>, ctx: &egui::Context) -> bool {
+    library.dialog.update(ctx);
+    if let Some(dir) = library.dialog.take_selected() {
+        library.scan(&dir);
+    }
+
+    let mut open = library.open;
+    let mut loaded = false;
+    egui::Window::new("Library").open(&mut open).show(ctx, |ui| {
+        ui.horizontal(|ui| {
+            if ui.button("Folder…").clicked() {
+                library.dialog.select_directory();
+            }
+            if let Some(dir) = library.dir.clone() {
+                if ui.button("Rescan").clicked() {
+                    library.scan(&dir);
+                }
+                ui.label(dir.display().to_string());
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("Search");
+            ui.add(egui::TextEdit::singleline(&mut library.search).hint_text("name, author, tags or notes"));
+        });
+        ui.separator();
+
+        let mut clicked = None;
+        egui::ScrollArea::vertical().max_height(250.0).show(ui, |ui| {
+            egui::Grid::new("Library entries").num_columns(4).striped(true).show(ui, |ui| {
+                sort_button(library, SortKey::Name, "Name", ui);
+                sort_button(library, SortKey::Author, "Author", ui);
+                ui.strong("Tags");
+                sort_button(library, SortKey::Rating, "Rating", ui);
+                ui.end_row();
+                for index in library.visible() {
+                    let file = &library.entries[index].file;
+                    if ui.selectable_label(library.selected == Some(index), file.name.as_str()).clicked() {
+                        clicked = Some(index);
+                    }
+                    ui.label(file.info.author.as_str());
+                    ui.label(file.info.tags.join(", "));
+                    ui.label(rating_text(file.info.rating));
+                    ui.end_row();
+                }
+            });
+        });
+        if let Some(index) = clicked {
+            library.preview(index, patch, name, info, sender);
+            loaded = true;
+        }
+        if library.skipped > 0 {
+            ui.weak(format!("{} files in the folder aren't patches", library.skipped));
+        }
+
+        ui.horizontal(|ui| {
+            let previewing = library.original.is_some();
+            if ui.add_enabled(previewing, egui::Button::new("Keep")).clicked() {
+                library.keep(name, info);
+            }
+            if ui.add_enabled(previewing, egui::Button::new("Cancel")).clicked() {
+                loaded |= library.cancel(patch, name, info, sender);
+            }
+        });
+        if let Some(index) = library.selected {
+            ui.separator();
+            draw_details(library, index, ui);
+        }
+        if let Some(error) = &library.error {
+            ui.colored_label(ui.visuals().error_fg_color, error.as_str());
+        }
+    });
+    if library.shown && !open {
+        library.commit();
+        loaded |= library.cancel(patch, name, info, sender);
+    }
+    library.open = open;
+    library.shown = open;
+    loaded
+}
@@ -8,9 +8,9 @@ fn main() -> eframe::Result<()> {
 
     let native_options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
-            .with_inner_size([365.0, 600.0])
-            .with_min_inner_size([365.0, 600.0])
-            .with_max_inner_size([365.0, 600.0]),
+            .with_inner_size([365.0, 760.0])
+            .with_min_inner_size([365.0, 760.0])
+            .with_max_inner_size([365.0, 760.0]),
         ..Default::default()
     };
     eframe::run_native(
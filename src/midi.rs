@@ -0,0 +1,543 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use egui::{Id, Response, Ui};
+use midir::{Ignore, MidiInput, MidiInputConnection, MidiInputPort, MidiOutput, MidiOutputConnection, MidiOutputPort};
+use crate::app::{Message, PatchUI};
+use crate::bank::{BankPatch, PatchBank};
+use crate::clock::{draw_clock_menu, Clock, MidiClockFollower};
+use crate::params::{self, ParamDesc, PARAMS};
+use crate::sysex::{decode_dump, is_dump, DumpSlot};
+use crate::bindings::{ParameterType, ParameterType_Attack, ParameterType_Cutoff, ParameterType_Decay, ParameterType_Gain, ParameterType_Release, ParameterType_Resonance, ParameterType_Sustain, Section, Section_Amp, Section_Filter};
+
+const CLIENT_NAME: &str = "BassSynth";
+const VIRTUAL_PORT_NAME: &str = "BassSynth In";
+
+/// Default CC assignments, following the General MIDI sound controller numbers where there is one.
+const DEFAULT_CC_MAP: [(u8, Section, ParameterType); 7] = [
+    (7, Section_Amp, ParameterType_Gain),
+    (71, Section_Filter, ParameterType_Resonance),
+    (72, Section_Amp, ParameterType_Release),
+    (73, Section_Amp, ParameterType_Attack),
+    (74, Section_Filter, ParameterType_Cutoff),
+    (75, Section_Amp, ParameterType_Decay),
+    (79, Section_Amp, ParameterType_Sustain),
+];
+
+fn target_name(section: Section, parameter: ParameterType) -> String {
+    params::find(section, parameter)
+        .map_or_else(|| format!("{} #{}", params::section_name(section), parameter), ParamDesc::full_name)
+}
+
+/// Routes a MIDI CC to a parameter. The controller position is inverted and shaped by `curve`
+/// (an exponent, so 1.0 is linear) before being scaled into `min..=max`, which is a fraction
+/// of the parameter's full range.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CcMapping {
+    pub cc: u8,
+    pub section: Section,
+    pub parameter: ParameterType,
+    pub min: f32,
+    pub max: f32,
+    pub invert: bool,
+    pub curve: f32,
+}
+
+impl CcMapping {
+    pub fn new(cc: u8, section: Section, parameter: ParameterType) -> Self {
+        CcMapping { cc, section, parameter, min: 0.0, max: 1.0, invert: false, curve: 1.0 }
+    }
+
+    pub fn normalise(&self, value: u8) -> f32 {
+        let mut x = value.min(127) as f32 / 127.0;
+        if self.invert {
+            x = 1.0 - x;
+        }
+        self.min + x.powf(self.curve) * (self.max - self.min)
+    }
+}
+
+impl Display for CcMapping {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} {} {} {} {} {}", self.cc, self.section, self.parameter, self.min, self.max, self.invert, self.curve)
+    }
+}
+
+impl FromStr for CcMapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [cc, section, parameter, min, max, invert, curve] = fields[..] else {
+            return Err(format!("Expected 7 fields in CC mapping \"{}\"", s));
+        };
+        let err = |e: &dyn Display| format!("Invalid CC mapping \"{}\": {}", s, e);
+        Ok(CcMapping {
+            cc: cc.parse().map_err(|e| err(&e))?,
+            section: section.parse().map_err(|e| err(&e))?,
+            parameter: parameter.parse().map_err(|e| err(&e))?,
+            min: min.parse().map_err(|e| err(&e))?,
+            max: max.parse().map_err(|e| err(&e))?,
+            invert: invert.parse().map_err(|e| err(&e))?,
+            curve: curve.parse().map_err(|e| err(&e))?,
+        })
+    }
+}
+
+pub fn default_mappings() -> Vec<CcMapping> {
+    DEFAULT_CC_MAP.iter()
+        .map(|&(cc, section, parameter)| CcMapping::new(cc, section, parameter))
+        .collect()
+}
+
+/// One mapping per line, as stored in the app's settings.
+pub fn mappings_to_string(mappings: &[CcMapping]) -> String {
+    mappings.iter().map(|m| format!("{}\n", m)).collect()
+}
+
+pub fn mappings_from_string(s: &str) -> Result<Vec<CcMapping>, String> {
+    s.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+fn learn_id() -> Id {
+    Id::new("midi_learn")
+}
+
+/// Adds a right-click menu to a control so the next CC received is mapped to its parameter.
+pub fn learn_menu(response: &Response, section: Section, parameter: ParameterType) {
+    response.context_menu(|ui| {
+        if ui.button("Learn MIDI CC").clicked() {
+            ui.ctx().data_mut(|d| d.insert_temp(learn_id(), Some((section, parameter))));
+            ui.close_menu();
+        }
+    });
+}
+
+fn learning(ctx: &egui::Context) -> Option<(Section, ParameterType)> {
+    ctx.data(|d| d.get_temp(learn_id())).flatten()
+}
+
+/// MIDI input that has to be handled on the UI thread because it changes the patch.
+#[derive(Debug, Clone)]
+pub enum MidiEvent {
+    ControlChange(u8, u8),
+    SysEx(Vec<u8>),
+}
+
+pub struct MidiInputState {
+    ports: Vec<(String, MidiInputPort)>,
+    connection: Option<(String, MidiInputConnection<MidiClockFollower>)>,
+    clock: Arc<Mutex<Clock>>,
+    events_tx: Sender<MidiEvent>,
+    events_rx: Receiver<MidiEvent>,
+    error: Option<String>,
+    pub mappings: Vec<CcMapping>,
+    pub show_mappings: bool,
+}
+
+impl MidiInputState {
+    pub fn new(clock: Arc<Mutex<Clock>>) -> Self {
+        let (events_tx, events_rx) = channel();
+        MidiInputState {
+            ports: Vec::new(),
+            connection: None,
+            clock,
+            events_tx,
+            events_rx,
+            error: None,
+            mappings: default_mappings(),
+            show_mappings: false,
+        }
+    }
+
+    pub fn refresh_ports(&mut self) {
+        self.ports.clear();
+        match MidiInput::new(CLIENT_NAME) {
+            Ok(input) => {
+                for port in input.ports() {
+                    if let Ok(name) = input.port_name(&port) {
+                        self.ports.push((name, port));
+                    }
+                }
+            }
+            Err(e) => self.error = Some(e.to_string()),
+        }
+    }
+
+    pub fn connected_port(&self) -> Option<&str> {
+        self.connection.as_ref().map(|(name, _)| name.as_str())
+    }
+
+    pub fn connect(&mut self, index: usize, sender: &Sender<Message>, ctx: &egui::Context) {
+        self.disconnect();
+        let Some((name, port)) = self.ports.get(index).cloned() else {
+            return;
+        };
+        let callback = midi_callback(sender.clone(), self.events_tx.clone(), self.clock.clone(), ctx.clone());
+        let result = new_input().and_then(|input| {
+            input
+                .connect(&port, CLIENT_NAME, callback, MidiClockFollower::default())
+                .map_err(|e| e.to_string())
+        });
+        self.finish_connect(name, result);
+    }
+
+    /// Opens a virtual input port that other applications (or `aconnect`) can route into.
+    #[cfg(unix)]
+    pub fn connect_virtual(&mut self, sender: &Sender<Message>, ctx: &egui::Context) {
+        use midir::os::unix::VirtualInput;
+        self.disconnect();
+        let callback = midi_callback(sender.clone(), self.events_tx.clone(), self.clock.clone(), ctx.clone());
+        let result = new_input().and_then(|input| {
+            input
+                .create_virtual(VIRTUAL_PORT_NAME, callback, MidiClockFollower::default())
+                .map_err(|e| e.to_string())
+        });
+        self.finish_connect(VIRTUAL_PORT_NAME.to_string(), result);
+    }
+
+    /// Reconnects to a port remembered from an earlier session, if it is still there.
+    pub fn connect_named(&mut self, name: &str, sender: &Sender<Message>, ctx: &egui::Context) {
+        #[cfg(unix)]
+        if name == VIRTUAL_PORT_NAME {
+            self.connect_virtual(sender, ctx);
+            return;
+        }
+        match self.ports.iter().position(|(port, _)| port == name) {
+            Some(index) => self.connect(index, sender, ctx),
+            None => log::warn!("MIDI input {} is no longer available", name),
+        }
+    }
+
+    fn finish_connect(&mut self, name: String, result: Result<MidiInputConnection<MidiClockFollower>, String>) {
+        match result {
+            Ok(connection) => {
+                self.connection = Some((name, connection));
+                self.error = None;
+            }
+            Err(e) => self.error = Some(e),
+        }
+    }
+
+    pub fn disconnect(&mut self) {
+        if let Some((_, connection)) = self.connection.take() {
+            connection.close();
+        }
+    }
+}
+
+fn new_input() -> Result<MidiInput, String> {
+    let mut input = MidiInput::new(CLIENT_NAME).map_err(|e| e.to_string())?;
+    input.ignore(Ignore::None);
+    Ok(input)
+}
+
+/// Builds the callback run on the MIDI thread. Notes go straight to `sender` and clock messages
+/// to the shared clock to keep latency down; everything else is passed to the UI thread.
+fn midi_callback(
+    sender: Sender<Message>,
+    events: Sender<MidiEvent>,
+    clock: Arc<Mutex<Clock>>,
+    ctx: egui::Context,
+) -> impl FnMut(u64, &[u8], &mut MidiClockFollower) + Send + 'static {
+    move |timestamp, bytes, follower| {
+        match *bytes {
+            [status] => {
+                if follower.handle(status, timestamp, &clock) {
+                    ctx.request_repaint();
+                }
+            }
+            [status, note, vel] if status & 0xF0 == 0x90 && vel > 0 => {
+                let _ = sender.send(Message::NoteOn(note, vel, Instant::now()));
+            }
+            [status, note, _] if status & 0xF0 == 0x80 || status & 0xF0 == 0x90 => {
+                let _ = sender.send(Message::NoteOff(note, Instant::now()));
+            }
+            [status, cc, value] if status & 0xF0 == 0xB0 => {
+                let _ = events.send(MidiEvent::ControlChange(cc, value));
+                ctx.request_repaint();
+            }
+            _ if is_dump(bytes) => {
+                let _ = events.send(MidiEvent::SysEx(bytes.to_vec()));
+                ctx.request_repaint();
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Applies the MIDI events received since the last frame to the patch and forwards them to the
+/// synth. A CC that arrives while a control is waiting to learn is mapped to it instead. Patch
+/// dumps are loaded into the patch being edited, or stored in the slot they were dumped from.
+/// Returns whether any controllers changed the patch.
+pub fn handle_midi_events(state: &mut MidiInputState, patch: &mut PatchUI, bank: &mut PatchBank, sender: &Sender<Message>, ctx: &egui::Context) -> bool {
+    let mut controlled = false;
+    while let Ok(event) = state.events_rx.try_recv() {
+        match event {
+            MidiEvent::ControlChange(cc, value) => {
+                if let Some((section, parameter)) = learning(ctx) {
+                    state.mappings.retain(|m| m.cc != cc);
+                    state.mappings.push(CcMapping::new(cc, section, parameter));
+                    ctx.data_mut(|d| d.remove::<Option<(Section, ParameterType)>>(learn_id()));
+                }
+                for mapping in state.mappings.iter().filter(|m| m.cc == cc) {
+                    let x = mapping.normalise(value);
+                    if let Some(msg) = patch.set_normalised(mapping.section, mapping.parameter, x) {
+                        sender.send(msg).unwrap();
+                        controlled = true;
+                    }
+                }
+            }
+            MidiEvent::SysEx(bytes) => match decode_dump(&bytes) {
+                Ok((DumpSlot::EditBuffer, received)) => {
+                    for msg in patch.load(received) {
+                        sender.send(msg).unwrap();
+                    }
+                }
+                Ok((DumpSlot::Bank(index), received)) if index < bank.patches.len() => {
+                    bank.patches[index] = BankPatch::numbered(index, received);
+                    bank.dirty = true;
+                }
+                Ok((DumpSlot::Bank(index), _)) => state.error = Some(format!("Received a dump for slot {}, but the bank has {}", index + 1, bank.patches.len())),
+                Err(e) => state.error = Some(format!("Received a bad dump: {}", e)),
+            },
+        }
+    }
+    controlled
+}
+
+pub fn draw_mapping_editor(state: &mut MidiInputState, ctx: &egui::Context) {
+    egui::Window::new("MIDI mappings")
+        .open(&mut state.show_mappings)
+        .show(ctx, |ui| {
+            if let Some((section, parameter)) = learning(ctx) {
+                ui.horizontal(|ui| {
+                    ui.label(format!("Move a controller to map {}", target_name(section, parameter)));
+                    if ui.button("Cancel").clicked() {
+                        ctx.data_mut(|d| d.remove::<Option<(Section, ParameterType)>>(learn_id()));
+                    }
+                });
+            }
+            let mut removed = None;
+            egui::Grid::new("MIDI mappings")
+                .num_columns(7)
+                .striped(true)
+                .show(ui, |ui| {
+                    for heading in ["CC", "Parameter", "Min", "Max", "Invert", "Curve", ""] {
+                        ui.strong(heading);
+                    }
+                    ui.end_row();
+                    for (index, mapping) in state.mappings.iter_mut().enumerate() {
+                        ui.add(egui::DragValue::new(&mut mapping.cc).range(0..=127));
+                        egui::ComboBox::from_id_source(("Mapping target", index))
+                            .selected_text(target_name(mapping.section, mapping.parameter))
+                            .show_ui(ui, |ui| {
+                                for desc in &PARAMS {
+                                    let selected = mapping.section == desc.section && mapping.parameter == desc.parameter;
+                                    if ui.selectable_label(selected, desc.full_name()).clicked() {
+                                        mapping.section = desc.section;
+                                        mapping.parameter = desc.parameter;
+                                    }
+                                }
+                            });
+                        ui.add(egui::DragValue::new(&mut mapping.min).speed(0.01).range(0.0..=1.0));
+                        ui.add(egui::DragValue::new(&mut mapping.max).speed(0.01).range(0.0..=1.0));
+                        ui.checkbox(&mut mapping.invert, "");
+                        ui.add(egui::DragValue::new(&mut mapping.curve).speed(0.05).range(0.1..=10.0));
+                        if ui.button("x").clicked() {
+                            removed = Some(index);
+                        }
+                        ui.end_row();
+                    }
+                });
+            if let Some(index) = removed {
+                state.mappings.remove(index);
+            }
+            if ui.button("Reset to defaults").clicked() {
+                state.mappings = default_mappings();
+            }
+            ui.label("Right-click a control to learn a new mapping.");
+        });
+}
+
+/// MIDI output, shared with the threads that send clock and dumps.
+#[derive(Default)]
+pub struct MidiOutputState {
+    ports: Vec<(String, MidiOutputPort)>,
+    name: Option<String>,
+    pub connection: Arc<Mutex<Option<MidiOutputConnection>>>,
+    error: Option<String>,
+}
+
+impl MidiOutputState {
+    pub fn refresh_ports(&mut self) {
+        self.ports.clear();
+        match MidiOutput::new(CLIENT_NAME) {
+            Ok(output) => {
+                for port in output.ports() {
+                    if let Ok(name) = output.port_name(&port) {
+                        self.ports.push((name, port));
+                    }
+                }
+            }
+            Err(e) => self.error = Some(e.to_string()),
+        }
+    }
+
+    pub fn connected_port(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn connect(&mut self, index: usize) {
+        self.disconnect();
+        let Some((name, port)) = self.ports.get(index).cloned() else {
+            return;
+        };
+        let result = MidiOutput::new(CLIENT_NAME)
+            .map_err(|e| e.to_string())
+            .and_then(|output| output.connect(&port, CLIENT_NAME).map_err(|e| e.to_string()));
+        match result {
+            Ok(connection) => {
+                *self.connection.lock().unwrap() = Some(connection);
+                self.name = Some(name);
+                self.error = None;
+            }
+            Err(e) => self.error = Some(e),
+        }
+    }
+
+    /// Reconnects to a port remembered from an earlier session, if it is still there.
+    pub fn connect_named(&mut self, name: &str) {
+        match self.ports.iter().position(|(port, _)| port == name) {
+            Some(index) => self.connect(index),
+            None => log::warn!("MIDI output {} is no longer available", name),
+        }
+    }
+
+    /// Sends SysEx messages one at a time, as some backends only accept one per call.
+    pub fn send_sysex(&mut self, messages: &[Vec<u8>]) {
+        let mut connection = self.connection.lock().unwrap();
+        let Some(connection) = connection.as_mut() else {
+            self.error = Some("No MIDI output is connected".to_string());
+            return;
+        };
+        let result = messages.iter().try_for_each(|message| connection.send(message));
+        self.error = result.err().map(|e| e.to_string());
+    }
+
+    pub fn disconnect(&mut self) {
+        if let Some(connection) = self.connection.lock().unwrap().take() {
+            connection.close();
+        }
+        self.name = None;
+    }
+}
+
+fn draw_output_menu(state: &mut MidiOutputState, ui: &mut Ui) {
+    if ui.button("Refresh ports").clicked() {
+        state.refresh_ports();
+    }
+    let mut selected = None;
+    for (index, (name, _)) in state.ports.iter().enumerate() {
+        let connected = state.connected_port() == Some(name.as_str());
+        if ui.selectable_label(connected, name).clicked() {
+            selected = Some(index);
+        }
+    }
+    if state.ports.is_empty() {
+        ui.label("No MIDI outputs found");
+    }
+    if let Some(index) = selected {
+        state.connect(index);
+        ui.close_menu();
+    }
+    if state.connected_port().is_some() && ui.button("Disconnect").clicked() {
+        state.disconnect();
+        ui.close_menu();
+    }
+    if let Some(error) = &state.error {
+        ui.colored_label(ui.visuals().error_fg_color, error);
+    }
+}
+
+pub fn draw_midi_menu(state: &mut MidiInputState, output: &mut MidiOutputState, sender: &Sender<Message>, ui: &mut Ui) {
+    if ui.button("Refresh ports").clicked() {
+        state.refresh_ports();
+    }
+    ui.separator();
+    let mut selected = None;
+    for (index, (name, _)) in state.ports.iter().enumerate() {
+        let connected = state.connected_port() == Some(name.as_str());
+        if ui.selectable_label(connected, name).clicked() {
+            selected = Some(index);
+        }
+    }
+    if state.ports.is_empty() {
+        ui.label("No MIDI inputs found");
+    }
+    if let Some(index) = selected {
+        state.connect(index, sender, ui.ctx());
+        ui.close_menu();
+    }
+    ui.separator();
+    #[cfg(unix)]
+    if ui.button("Open virtual port").clicked() {
+        state.connect_virtual(sender, ui.ctx());
+        ui.close_menu();
+    }
+    if state.connected_port().is_some() && ui.button("Disconnect").clicked() {
+        state.disconnect();
+        ui.close_menu();
+    }
+    ui.separator();
+    ui.menu_button("Output", |ui| draw_output_menu(output, ui));
+    ui.menu_button("Clock", |ui| draw_clock_menu(&state.clock, ui));
+    if ui.button("Edit mappings").clicked() {
+        state.show_mappings = true;
+        ui.close_menu();
+    }
+    if let Some(error) = &state.error {
+        ui.colored_label(ui.visuals().error_fg_color, error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bindings::{ParameterType_Emphasis, Section_Osc2, ParameterType_Fine};
+
+    #[test]
+    fn cc_mapping_round_trips_through_text() {
+        let mapping = CcMapping {
+            cc: 74,
+            section: Section_Filter,
+            parameter: ParameterType_Emphasis,
+            min: 0.25,
+            max: 0.875,
+            invert: true,
+            curve: 2.5,
+        };
+        assert_eq!(mapping.to_string().parse::<CcMapping>(), Ok(mapping));
+        let plain = CcMapping::new(0, Section_Osc2, ParameterType_Fine);
+        assert_eq!(plain.to_string().parse::<CcMapping>(), Ok(plain));
+    }
+
+    #[test]
+    fn mappings_round_trip_through_settings() {
+        let mappings = default_mappings();
+        assert_eq!(mappings_from_string(&mappings_to_string(&mappings)), Ok(mappings));
+        assert_eq!(mappings_from_string(""), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn bad_cc_mappings_are_rejected() {
+        assert!("74 4 6 0 1 false".parse::<CcMapping>().is_err());
+        assert!("256 4 6 0 1 false 1".parse::<CcMapping>().is_err());
+        assert!("74 4 6 0 1 maybe 1".parse::<CcMapping>().is_err());
+    }
+}
@@ -0,0 +1,356 @@
+use std::path::Path;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use egui_file_dialog::FileDialog;
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+use crate::app::{Message, Note};
+use crate::clock::Clock;
+
+const BEATS_PER_BAR: f64 = 4.0;
+const DEFAULT_TEMPO: f64 = 120.0;
+const TICK: Duration = Duration::from_millis(1);
+const IDLE_POLL: Duration = Duration::from_millis(10);
+/// How often the playhead is redrawn while playing, about once a frame.
+const REPAINT: Duration = Duration::from_millis(16);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NoteEvent {
+    On(Note, u8),
+    Off(Note),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FileEvent {
+    pub beat: f64,
+    pub track: usize,
+    pub channel: u8,
+    pub event: NoteEvent,
+}
+
+/// The note events of a Standard MIDI File, with times converted to beats.
+pub struct MidiFile {
+    pub name: String,
+    pub track_names: Vec<String>,
+    pub events: Vec<FileEvent>,
+    /// Tempo changes as (beat, bpm), sorted by beat.
+    pub tempo_map: Vec<(f64, f64)>,
+    pub length: f64,
+}
+
+impl MidiFile {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let bytes = std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let smf = Smf::parse(&bytes).map_err(|e| format!("Invalid MIDI file: {}", e))?;
+        let ticks_per_beat = match smf.header.timing {
+            Timing::Metrical(ticks) => ticks.as_int() as f64,
+            Timing::Timecode(..) => return Err("SMPTE timed MIDI files are not supported".to_string()),
+        };
+
+        let mut track_names = Vec::new();
+        let mut events = Vec::new();
+        let mut tempo_map = Vec::new();
+        for (track, track_events) in smf.tracks.iter().enumerate() {
+            let mut name = format!("Track {}", track + 1);
+            let mut tick = 0u64;
+            for event in track_events {
+                tick += event.delta.as_int() as u64;
+                let beat = tick as f64 / ticks_per_beat;
+                match event.kind {
+                    TrackEventKind::Midi { channel, message } => {
+                        let event = match message {
+                            MidiMessage::NoteOn { key, vel } if vel > 0 => NoteEvent::On(key.as_int(), vel.as_int()),
+                            MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => NoteEvent::Off(key.as_int()),
+                            _ => continue,
+                        };
+                        events.push(FileEvent { beat, track, channel: channel.as_int(), event });
+                    }
+                    TrackEventKind::Meta(MetaMessage::Tempo(micros_per_beat)) => {
+                        tempo_map.push((beat, 60_000_000.0 / micros_per_beat.as_int() as f64));
+                    }
+                    TrackEventKind::Meta(MetaMessage::TrackName(bytes)) => {
+                        name = String::from_utf8_lossy(bytes).trim().to_string();
+                    }
+                    _ => {}
+                }
+            }
+            track_names.push(name);
+        }
+        // The sort is stable, so events at the same time keep their order from the file.
+        events.sort_by(|a, b| a.beat.total_cmp(&b.beat));
+        tempo_map.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let length = events.last().map_or(0.0, |e| e.beat);
+        let name = path.file_name().map_or_else(String::new, |n| n.to_string_lossy().to_string());
+
+        Ok(MidiFile { name, track_names, events, tempo_map, length })
+    }
+
+    pub fn tempo_at(&self, beat: f64) -> f64 {
+        self.tempo_map.iter()
+            .take_while(|(b, _)| *b <= beat)
+            .last()
+            .map_or(DEFAULT_TEMPO, |(_, bpm)| *bpm)
+    }
+
+    fn first_event_from(&self, beat: f64) -> usize {
+        self.events.partition_point(|e| e.beat < beat)
+    }
+}
+
+/// State shared between the UI and the playback thread.
+pub struct PlayerState {
+    pub file: Option<MidiFile>,
+    /// Only play this track, or all of them.
+    pub track: Option<usize>,
+    /// Only play this channel, or all of them.
+    pub channel: Option<u8>,
+    pub playing: bool,
+    pub looping: bool,
+    pub loop_start: f64,
+    pub loop_end: f64,
+    /// Play at the app's tempo rather than the tempo recorded in the file.
+    pub tempo_override: bool,
+    pub position: f64,
+    /// Set by the UI to move the playhead; taken by the playback thread.
+    pub seek: Option<f64>,
+}
+
+impl Default for PlayerState {
+    fn default() -> Self {
+        PlayerState {
+            file: None,
+            track: None,
+            channel: None,
+            playing: false,
+            looping: false,
+            loop_start: 0.0,
+            loop_end: 4.0 * BEATS_PER_BAR,
+            tempo_override: false,
+            position: 0.0,
+            seek: None,
+        }
+    }
+}
+
+impl PlayerState {
+    fn plays(&self, event: &FileEvent) -> bool {
+        self.track.is_none_or(|t| t == event.track) && self.channel.is_none_or(|c| c == event.channel)
+    }
+}
+
+/// Plays the loaded file until the UI goes away, advancing in beats so the tempo can be
+/// changed (or overridden) while playing. With the tempo overridden or an external clock it
+/// follows the clock's beats, so it stays in step with the sequencer and the clock source.
+pub fn run_player(shared: Arc<Mutex<PlayerState>>, clock: Arc<Mutex<Clock>>, sender: Sender<Message>, ctx: egui::Context) {
+    let mut sounding: Vec<Note> = Vec::new();
+    let mut next_event = 0;
+    let mut last = Instant::now();
+    let mut last_beat = 0.0;
+    let mut was_running = false;
+
+    loop {
+        let mut messages = Vec::new();
+        {
+            let mut guard = shared.lock().unwrap();
+            let state = &mut *guard;
+            let now = Instant::now();
+            let elapsed = now - last;
+            last = now;
+
+            let (clock_beats, following) = {
+                let mut clock = clock.lock().unwrap();
+                let beat = clock.advance();
+                // The beat count goes back to zero when the transport starts.
+                let clock_beats = (beat - last_beat).max(0.0);
+                last_beat = beat;
+                if clock.is_external() && clock.running != was_running {
+                    state.playing = clock.running;
+                    if clock.running {
+                        state.seek = Some(if state.looping { state.loop_start } else { 0.0 });
+                    }
+                }
+                was_running = clock.running;
+                (clock_beats, clock.is_external())
+            };
+            if let Some(beat) = state.seek.take() {
+                state.position = beat;
+                next_event = state.file.as_ref().map_or(0, |f| f.first_event_from(beat));
+                messages.extend(sounding.drain(..).map(|note| Message::NoteOff(note, now)));
+            }
+            let Some(file) = state.file.as_ref().filter(|_| state.playing) else {
+                drop(guard);
+                messages.extend(sounding.drain(..).map(|note| Message::NoteOff(note, now)));
+                if messages.into_iter().any(|msg| sender.send(msg).is_err()) || Arc::strong_count(&shared) == 1 {
+                    return;
+                }
+                thread::sleep(IDLE_POLL);
+                continue;
+            };
+
+            let mut position = if state.tempo_override || following {
+                state.position + clock_beats
+            } else {
+                state.position + elapsed.as_secs_f64() * file.tempo_at(state.position) / 60.0
+            };
+            let end = if state.looping { state.loop_end } else { file.length };
+
+            while let Some(event) = file.events.get(next_event).filter(|e| e.beat < position.min(end)) {
+                next_event += 1;
+                if !state.plays(event) {
+                    continue;
+                }
+                match event.event {
+                    NoteEvent::On(note, vel) => {
+                        sounding.push(note);
+                        messages.push(Message::NoteOn(note, vel, now));
+                    }
+                    NoteEvent::Off(note) => {
+                        if let Some(index) = sounding.iter().position(|n| *n == note) {
+                            sounding.remove(index);
+                            messages.push(Message::NoteOff(note, now));
+                        }
+                    }
+                }
+            }
+
+            if position >= end {
+                messages.extend(sounding.drain(..).map(|note| Message::NoteOff(note, now)));
+                if state.looping && state.loop_start < end {
+                    position = state.loop_start;
+                    next_event = file.first_event_from(position);
+                } else {
+                    state.playing = false;
+                    position = 0.0;
+                    next_event = 0;
+                }
+            }
+            state.position = position;
+        }
+
+        for msg in messages {
+            if sender.send(msg).is_err() {
+                return;
+            }
+        }
+        ctx.request_repaint_after(REPAINT);
+        thread::sleep(TICK);
+    }
+}
+
+pub struct MidiFilePlayer {
+    pub shared: Arc<Mutex<PlayerState>>,
+    pub open: bool,
+    dialog: FileDialog,
+    error: Option<String>,
+}
+
+impl MidiFilePlayer {
+    pub fn new(shared: Arc<Mutex<PlayerState>>) -> Self {
+        MidiFilePlayer { shared, open: false, dialog: FileDialog::new(), error: None }
+    }
+}
+
+fn format_position(beat: f64) -> String {
+    let bar = (beat / BEATS_PER_BAR).floor();
+    format!("{}.{}", bar as u32 + 1, (beat - bar * BEATS_PER_BAR).floor() as u32 + 1)
+}
+
+pub fn draw_midi_file_player(player: &mut MidiFilePlayer, clock: &Mutex<Clock>, ctx: &egui::Context) {
+    player.dialog.update(ctx);
+    if let Some(path) = player.dialog.take_selected() {
+        match MidiFile::load(&path) {
+            Ok(file) => {
+                let mut state = player.shared.lock().unwrap();
+                state.playing = false;
+                state.seek = Some(0.0);
+                state.track = None;
+                state.loop_start = 0.0;
+                state.loop_end = (file.length / BEATS_PER_BAR).ceil() * BEATS_PER_BAR;
+                state.file = Some(file);
+                player.error = None;
+            }
+            Err(e) => player.error = Some(e),
+        }
+    }
+
+    let MidiFilePlayer { shared, open, dialog, error } = player;
+    egui::Window::new("MIDI file").open(open).show(ctx, |ui| {
+        let mut state = shared.lock().unwrap();
+        ui.horizontal(|ui| {
+            if ui.button("Open").clicked() {
+                dialog.select_file();
+            }
+            ui.label(state.file.as_ref().map_or("No file loaded", |f| f.name.as_str()));
+        });
+        if let Some(error) = error {
+            ui.colored_label(ui.visuals().error_fg_color, error.as_str());
+        }
+        let Some(file) = &state.file else {
+            return;
+        };
+        let track_names = file.track_names.clone();
+        let length = file.length;
+
+        egui::Grid::new("MIDI file").num_columns(2).show(ui, |ui| {
+            ui.label("Track");
+            let selected = state.track.map_or("All".to_string(), |t| track_names[t].clone());
+            egui::ComboBox::from_id_source("MIDI file track")
+                .selected_text(selected)
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut state.track, None, "All");
+                    for (index, name) in track_names.iter().enumerate() {
+                        ui.selectable_value(&mut state.track, Some(index), name);
+                    }
+                });
+            ui.end_row();
+
+            ui.label("Channel");
+            let selected = state.channel.map_or("All".to_string(), |c| format!("{}", c + 1));
+            egui::ComboBox::from_id_source("MIDI file channel")
+                .selected_text(selected)
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut state.channel, None, "All");
+                    for channel in 0..16 {
+                        ui.selectable_value(&mut state.channel, Some(channel), format!("{}", channel + 1));
+                    }
+                });
+            ui.end_row();
+
+            ui.label("Loop");
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut state.looping, "");
+                let bars = length / BEATS_PER_BAR;
+                let mut start = state.loop_start / BEATS_PER_BAR + 1.0;
+                let mut end = state.loop_end / BEATS_PER_BAR + 1.0;
+                ui.add(egui::DragValue::new(&mut start).range(1.0..=bars.ceil()).speed(0.1).fixed_decimals(0).prefix("bar "));
+                ui.add(egui::DragValue::new(&mut end).range(start..=bars.ceil() + 1.0).speed(0.1).fixed_decimals(0).prefix("to "));
+                state.loop_start = (start.round() - 1.0) * BEATS_PER_BAR;
+                state.loop_end = (end.round() - 1.0) * BEATS_PER_BAR;
+            });
+            ui.end_row();
+
+            ui.label("Tempo");
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut state.tempo_override, "Override");
+                if state.tempo_override {
+                    ui.add(egui::DragValue::new(&mut clock.lock().unwrap().tempo).range(40.0..=300.0).suffix(" bpm"));
+                } else if let Some(file) = &state.file {
+                    ui.label(format!("{:.1} bpm", file.tempo_at(state.position)));
+                }
+            });
+            ui.end_row();
+        });
+
+        ui.horizontal(|ui| {
+            let label = if state.playing { "Stop" } else { "Play" };
+            if ui.button(label).clicked() {
+                state.playing = !state.playing;
+            }
+            if ui.button("|<").on_hover_text("Rewind").clicked() {
+                state.seek = Some(if state.looping { state.loop_start } else { 0.0 });
+            }
+            ui.label(format!("{} / {}", format_position(state.position), format_position(length)));
+        });
+    });
+}
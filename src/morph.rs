@@ -0,0 +1,152 @@
+use std::sync::mpsc::Sender;
+use egui::{Color32, Pos2, Rounding, Sense, Stroke, Vec2};
+use crate::app::{Message, PatchUI};
+use crate::bank::PatchBank;
+use crate::bindings::{Patch, Section_Global};
+use crate::params::PARAMS;
+
+const CORNER_NAMES: [&str; 4] = ["A", "B", "C", "D"];
+const PAD_SIZE: f32 = 160.0;
+
+/// Blends every parameter of two patches; see `ParamDesc::interpolate`.
+pub fn morph_patches(a: &Patch, b: &Patch, t: f32) -> Patch {
+    let (a, b) = (PatchUI::from(*a), PatchUI::from(*b));
+    let mut morphed = PatchUI::default();
+    for desc in PARAMS.iter().filter(|d| d.section != Section_Global) {
+        if let (Some(from), Some(to)) = (a.get(desc.section, desc.parameter), b.get(desc.section, desc.parameter)) {
+            morphed.set(desc.section, desc.parameter, desc.interpolate(from, to, t));
+        }
+    }
+    Patch::from(&morphed)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum MorphMode {
+    /// Between A and B.
+    Slider,
+    /// Across A and B along the top and C and D along the bottom.
+    Pad,
+}
+
+pub struct Morph {
+    pub open: bool,
+    corners: [Option<(String, Patch)>; 4],
+    mode: MorphMode,
+    position: f32,
+    pad: Vec2,
+}
+
+impl Default for Morph {
+    fn default() -> Self {
+        Morph { open: false, corners: [None, None, None, None], mode: MorphMode::Slider, position: 0.0, pad: Vec2::ZERO }
+    }
+}
+
+impl Morph {
+    /// The patch at the current position, once the corners it needs are set.
+    fn morphed(&self) -> Option<Patch> {
+        let corner = |index: usize| self.corners[index].as_ref().map(|(_, patch)| patch);
+        match self.mode {
+            MorphMode::Slider => Some(morph_patches(corner(0)?, corner(1)?, self.position)),
+            MorphMode::Pad => {
+                let top = morph_patches(corner(0)?, corner(1)?, self.pad.x);
+                let bottom = morph_patches(corner(2)?, corner(3)?, self.pad.x);
+                Some(morph_patches(&top, &bottom, self.pad.y))
+            }
+        }
+    }
+
+    /// Sends the changes that take the patch being edited to the current position.
+    fn stream(&self, patch: &mut PatchUI, sender: &Sender<Message>) {
+        if let Some(morphed) = self.morphed() {
+            for msg in patch.load(morphed) {
+                sender.send(msg).unwrap();
+            }
+        }
+    }
+}
+
+fn draw_corner(morph: &mut Morph, index: usize, patch: &PatchUI, bank: &PatchBank, ui: &mut egui::Ui) {
+    ui.strong(CORNER_NAMES[index]);
+    let name = morph.corners[index].as_ref().map_or("(empty)", |(name, _)| name.as_str());
+    let mut selected = None;
+    egui::ComboBox::from_id_source(("Morph corner", index))
+        .selected_text(name)
+        .width(120.0)
+        .show_ui(ui, |ui| {
+            for (slot, bank_patch) in bank.patches.iter().enumerate() {
+                if ui.selectable_label(false, format!("{} {}", slot + 1, bank_patch.name)).clicked() {
+                    selected = Some((bank_patch.name.clone(), bank_patch.patch));
+                }
+            }
+        });
+    if ui.button("Current").on_hover_text("Use the patch being edited").clicked() {
+        selected = Some(("Current".to_string(), Patch::from(patch)));
+    }
+    if selected.is_some() {
+        morph.corners[index] = selected;
+    }
+    ui.end_row();
+}
+
+/// Draws the XY pad, returning true if the point was moved.
+fn draw_pad(morph: &mut Morph, ui: &mut egui::Ui) -> bool {
+    let (rect, response) = ui.allocate_exact_size(Vec2::splat(PAD_SIZE), Sense::click_and_drag());
+    let mut moved = false;
+    if let Some(pos) = response.interact_pointer_pos() {
+        let pad = ((pos - rect.min) / rect.size()).clamp(Vec2::ZERO, Vec2::splat(1.0));
+        moved = pad != morph.pad;
+        morph.pad = pad;
+    }
+
+    let painter = ui.painter_at(rect);
+    let visuals = ui.visuals();
+    painter.rect(rect, Rounding::same(2.0), visuals.extreme_bg_color, Stroke::new(1.0, Color32::DARK_GRAY));
+    for (index, name) in CORNER_NAMES.iter().enumerate() {
+        let x = if index % 2 == 0 { rect.left() + 8.0 } else { rect.right() - 8.0 };
+        let y = if index < 2 { rect.top() + 8.0 } else { rect.bottom() - 8.0 };
+        painter.text(Pos2::new(x, y), egui::Align2::CENTER_CENTER, name, egui::FontId::default(), visuals.text_color());
+    }
+    painter.circle_filled(rect.min + morph.pad * rect.size(), 6.0, visuals.selection.bg_fill);
+    moved
+}
+
+/// Morphs between patches from the bank or the one being edited, sending the blend to the synth
+/// as the slider or the point on the pad moves. Returns whether it did.
+pub fn draw_morph(morph: &mut Morph, patch: &mut PatchUI, bank: &PatchBank, sender: &Sender<Message>, ctx: &egui::Context) -> bool {
+    let mut open = morph.open;
+    let mut streamed = false;
+    egui::Window::new("Morph").open(&mut open).show(ctx, |ui| {
+        ui.horizontal(|ui| {
+            ui.selectable_value(&mut morph.mode, MorphMode::Slider, "A/B");
+            ui.selectable_value(&mut morph.mode, MorphMode::Pad, "XY");
+        });
+        let corners = if morph.mode == MorphMode::Slider { 2 } else { 4 };
+        egui::Grid::new("Morph corners").num_columns(3).show(ui, |ui| {
+            for index in 0..corners {
+                draw_corner(morph, index, patch, bank, ui);
+            }
+        });
+        ui.separator();
+
+        let ready = morph.corners[..corners].iter().all(Option::is_some);
+        let moved = ui.add_enabled_ui(ready, |ui| match morph.mode {
+            MorphMode::Slider => ui.horizontal(|ui| {
+                ui.label("A");
+                let slider = ui.add(egui::Slider::new(&mut morph.position, 0.0..=1.0).show_value(false));
+                ui.label("B");
+                slider.changed()
+            }).inner,
+            MorphMode::Pad => draw_pad(morph, ui),
+        }).inner;
+        if !ready {
+            ui.label(format!("Choose patches for {} to morph.", CORNER_NAMES[..corners].join(", ")));
+        }
+        if ready && moved {
+            morph.stream(patch, sender);
+            streamed = true;
+        }
+    });
+    morph.open = open;
+    streamed
+}
@@ -0,0 +1,366 @@
+use crate::synth::{FilterMode, ParamValue, SynthCommand, SynthParameter, SynthSection, Waveform};
+use crate::bindings::{FilterModeEnum_HP, FilterModeEnum_LP, ParameterType, ParameterType_Attack, ParameterType_Coarse, ParameterType_Cutoff, ParameterType_Decay, ParameterType_Emphasis, ParameterType_Fine, ParameterType_Frequency, ParameterType_Gain, ParameterType_Mix, ParameterType_Mode, ParameterType_Release, ParameterType_Resonance, ParameterType_Sustain, ParameterType_Waveform, Scale, Scale_SCALE_DB, Scale_SCALE_FreqExp, Scale_SCALE_Lin, Scale_SCALE_LogTime, Scale_SCALE_PCT, Scale_SCALE_STEP, Section, Section_Amp, Section_Filter, Section_Global, Section_Osc1, Section_Osc2, Section_Osc3, WaveformEnum_SAW, WaveformEnum_SIN, WaveformEnum_SQR};
+
+const SECTION_NAMES: [&str; 6] = ["Global", "Osc 1", "Osc 2", "Osc 3", "Filter", "Amp"];
+
+/// dB per decade of control position on a gain control; 80 makes the position the fourth
+/// root of the amplitude, which is close to how loudness is heard.
+const DB_CURVE: f32 = 80.0;
+
+const WAVEFORMS: &[(u8, &str)] = &[(WaveformEnum_SIN, "Sin"), (WaveformEnum_SAW, "Saw"), (WaveformEnum_SQR, "Sqr")];
+const FILTER_MODES: &[(u8, &str)] = &[(FilterModeEnum_HP, "HP"), (FilterModeEnum_LP, "LP")];
+
+/// Which type of `ParamValue` the parameter takes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueKind {
+    Float,
+    Int8,
+    Uint8,
+    Waveform,
+    FilterMode,
+}
+
+/// Everything the UI needs to know about one (section, parameter) pair. Values are handled
+/// as `f32` in the parameter's own units and converted to the wire type at the edges.
+#[derive(Debug)]
+pub struct ParamDesc {
+    pub section: Section,
+    pub parameter: ParameterType,
+    pub name: &'static str,
+    /// Short label shown next to the control.
+    pub label: &'static str,
+    pub unit: &'static str,
+    pub min: f32,
+    pub max: f32,
+    pub default: f32,
+    pub scale: Scale,
+    pub kind: ValueKind,
+    /// Names of the values of a stepped parameter, in display order.
+    pub choices: &'static [(u8, &'static str)],
+}
+
+const fn desc(
+    section: Section,
+    parameter: ParameterType,
+    (name, label): (&'static str, &'static str),
+    unit: &'static str,
+    (min, max, default): (f32, f32, f32),
+    scale: Scale,
+    kind: ValueKind,
+) -> ParamDesc {
+    ParamDesc { section, parameter, name, label, unit, min, max, default, scale, kind, choices: &[] }
+}
+
+const fn choice(section: Section, parameter: ParameterType, name: &'static str, kind: ValueKind, choices: &'static [(u8, &'static str)], default: u8) -> ParamDesc {
+    ParamDesc {
+        section,
+        parameter,
+        name,
+        label: name,
+        unit: "",
+        // The enums the synth uses number their values from zero.
+        min: 0.0,
+        max: (choices.len() - 1) as f32,
+        default: default as f32,
+        scale: Scale_SCALE_STEP,
+        kind,
+        choices,
+    }
+}
+
+const fn waveform(section: Section) -> ParamDesc {
+    choice(section, ParameterType_Waveform, "Waveform", ValueKind::Waveform, WAVEFORMS, WaveformEnum_SAW)
+}
+
+const fn coarse(section: Section) -> ParamDesc {
+    desc(section, ParameterType_Coarse, ("Coarse", "Coarse"), "st", (-24.0, 24.0, 0.0), Scale_SCALE_STEP, ValueKind::Int8)
+}
+
+const fn fine(section: Section) -> ParamDesc {
+    desc(section, ParameterType_Fine, ("Fine", "Fine"), "ct", (-50.0, 50.0, 0.0), Scale_SCALE_Lin, ValueKind::Int8)
+}
+
+/// Gains are in dB, with the minimum meaning silence.
+const fn gain(section: Section) -> ParamDesc {
+    desc(section, ParameterType_Gain, ("Gain", "Gain"), "dB", (i8::MIN as f32, 6.0, 0.0), Scale_SCALE_DB, ValueKind::Int8)
+}
+
+const fn envelope(section: Section, parameter: ParameterType) -> ParamDesc {
+    match parameter {
+        ParameterType_Attack => desc(section, parameter, ("Attack", "A"), "ms", (20.0, 2000.0, 20.0), Scale_SCALE_LogTime, ValueKind::Float),
+        ParameterType_Decay => desc(section, parameter, ("Decay", "D"), "ms", (20.0, 2000.0, 200.0), Scale_SCALE_LogTime, ValueKind::Float),
+        ParameterType_Sustain => desc(section, parameter, ("Sustain", "S"), "%", (0.0, 1.0, 0.5), Scale_SCALE_PCT, ValueKind::Float),
+        _ => desc(section, parameter, ("Release", "R"), "ms", (20.0, 2000.0, 500.0), Scale_SCALE_LogTime, ValueKind::Float),
+    }
+}
+
+pub static PARAMS: [ParamDesc; 27] = [
+    desc(Section_Global, ParameterType_Frequency, ("Frequency", "Tune"), "Hz", (415.0, 466.0, 440.0), Scale_SCALE_Lin, ValueKind::Float),
+    desc(Section_Global, ParameterType_Mix, ("Mix", "Mix"), "%", (0.0, 1.0, 1.0), Scale_SCALE_PCT, ValueKind::Float),
+    waveform(Section_Osc1),
+    coarse(Section_Osc1),
+    fine(Section_Osc1),
+    gain(Section_Osc1),
+    waveform(Section_Osc2),
+    coarse(Section_Osc2),
+    fine(Section_Osc2),
+    gain(Section_Osc2),
+    waveform(Section_Osc3),
+    coarse(Section_Osc3),
+    fine(Section_Osc3),
+    gain(Section_Osc3),
+    choice(Section_Filter, ParameterType_Mode, "Mode", ValueKind::FilterMode, FILTER_MODES, FilterModeEnum_LP),
+    desc(Section_Filter, ParameterType_Cutoff, ("Cutoff", "Freq"), "Hz", (20.0, 20000.0, 20000.0), Scale_SCALE_FreqExp, ValueKind::Float),
+    desc(Section_Filter, ParameterType_Resonance, ("Resonance", "Reso"), "", (0.0, 255.0, 0.0), Scale_SCALE_Lin, ValueKind::Uint8),
+    desc(Section_Filter, ParameterType_Emphasis, ("Emphasis", "Emph"), "%", (0.0, 1.0, 0.0), Scale_SCALE_PCT, ValueKind::Float),
+    envelope(Section_Filter, ParameterType_Attack),
+    envelope(Section_Filter, ParameterType_Decay),
+    envelope(Section_Filter, ParameterType_Sustain),
+    envelope(Section_Filter, ParameterType_Release),
+    gain(Section_Amp),
+    envelope(Section_Amp, ParameterType_Attack),
+    envelope(Section_Amp, ParameterType_Decay),
+    envelope(Section_Amp, ParameterType_Sustain),
+    envelope(Section_Amp, ParameterType_Release),
+];
+
+pub fn section_name(section: Section) -> &'static str {
+    SECTION_NAMES.get(section as usize).unwrap_or(&"?")
+}
+
+pub fn find(section: Section, parameter: ParameterType) -> Option<&'static ParamDesc> {
+    PARAMS.iter().find(|p| p.section == section && p.parameter == parameter)
+}
+
+/// Looks up a parameter that is known to be in the table, such as one with its own control.
+pub fn param(section: Section, parameter: ParameterType) -> &'static ParamDesc {
+    find(section, parameter).unwrap_or_else(|| panic!("No parameter {} in section {}", parameter, section))
+}
+
+impl ParamDesc {
+    pub fn full_name(&self) -> String {
+        format!("{} {}", section_name(self.section), self.name)
+    }
+
+    pub fn is_stepped(&self) -> bool {
+        !self.choices.is_empty()
+    }
+
+    /// Limits a value to the parameter's range, rounding it if the wire type is an integer. A
+    /// value that isn't a number becomes the default.
+    pub fn clamp(&self, value: f32) -> f32 {
+        if value.is_nan() {
+            return self.default;
+        }
+        let value = value.clamp(self.min, self.max);
+        if self.kind == ValueKind::Float { value } else { value.round() }
+    }
+
+    /// Maps a value to a control position in `0.0..=1.0`, following the parameter's scale so
+    /// that equal distances on a control sound like equal changes.
+    pub fn normalise(&self, value: f32) -> f32 {
+        if self.is_stepped() {
+            let index = self.choices.iter().position(|(v, _)| *v as f32 == value).unwrap_or(0);
+            return index as f32 / (self.choices.len() - 1).max(1) as f32;
+        }
+        let value = value.clamp(self.min, self.max);
+        let x = match self.scale {
+            Scale_SCALE_FreqExp | Scale_SCALE_LogTime => (value / self.min).ln() / (self.max / self.min).ln(),
+            // Fourth root of the amplitude relative to full scale, with the minimum at zero.
+            Scale_SCALE_DB if value <= self.min => 0.0,
+            Scale_SCALE_DB => 10f32.powf((value - self.max) / DB_CURVE),
+            _ => (value - self.min) / (self.max - self.min),
+        };
+        x.clamp(0.0, 1.0)
+    }
+
+    /// Maps a control position in `0.0..=1.0` to a value. The inverse of `normalise`.
+    pub fn denormalise(&self, x: f32) -> f32 {
+        let x = x.clamp(0.0, 1.0);
+        if self.is_stepped() {
+            let index = (x * (self.choices.len() - 1) as f32).round() as usize;
+            return self.choices[index].0 as f32;
+        }
+        let value = match self.scale {
+            Scale_SCALE_FreqExp | Scale_SCALE_LogTime => self.min * (self.max / self.min).powf(x),
+            Scale_SCALE_DB if x <= 0.0 => self.min,
+            Scale_SCALE_DB => self.max + DB_CURVE * x.log10(),
+            _ => self.min + x * (self.max - self.min),
+        };
+        self.clamp(value)
+    }
+
+    /// Blends from `a` at `t = 0` to `b` at `t = 1`: geometrically for frequencies and times,
+    /// linearly in the parameter's own unit (dB for gains) otherwise. Choices switch halfway.
+    pub fn interpolate(&self, a: f32, b: f32, t: f32) -> f32 {
+        let (a, b, t) = (self.clamp(a), self.clamp(b), t.clamp(0.0, 1.0));
+        if self.is_stepped() {
+            return if t < 0.5 { a } else { b };
+        }
+        let value = match self.scale {
+            Scale_SCALE_FreqExp | Scale_SCALE_LogTime => a * (b / a).powf(t),
+            _ => a + t * (b - a),
+        };
+        self.clamp(value)
+    }
+
+    pub fn encode(&self, value: f32) -> ParamValue {
+        let value = self.clamp(value);
+        match self.kind {
+            ValueKind::Float => ParamValue::Float(value),
+            ValueKind::Int8 => ParamValue::Int8(value as i8),
+            ValueKind::Uint8 => ParamValue::Uint8(value as u8),
+            ValueKind::Waveform => ParamValue::Waveform(Waveform::try_from(value as u8).unwrap_or(Waveform::Saw)),
+            ValueKind::FilterMode => ParamValue::FilterMode(FilterMode::try_from(value as u8).unwrap_or(FilterMode::LowPass)),
+        }
+    }
+
+    /// The command that sets this parameter to `value`.
+    pub fn command(&self, value: f32) -> SynthCommand {
+        let section = SynthSection::try_from(self.section).expect("registry sections are valid");
+        let parameter = SynthParameter::try_from(self.parameter).expect("registry parameters are valid");
+        SynthCommand::new(section, parameter, self.encode(value)).expect("registry kinds match the synth's")
+    }
+
+    /// Writes a value exactly, in the parameter's own unit, for files.
+    pub fn write_value(&self, value: f32) -> String {
+        if let Some((_, name)) = self.choices.iter().find(|(v, _)| *v as f32 == value) {
+            return name.to_string();
+        }
+        if self.unit.is_empty() || self.scale == Scale_SCALE_PCT {
+            value.to_string()
+        } else {
+            format!("{} {}", value, self.unit)
+        }
+    }
+
+    /// Reads a value written by `write_value`. Unlike `parse`, values out of range are an error.
+    pub fn read_value(&self, text: &str) -> Result<f32, String> {
+        if let Some((value, _)) = self.choices.iter().find(|(_, name)| name.eq_ignore_ascii_case(text)) {
+            return Ok(*value as f32);
+        }
+        let invalid = || format!("Invalid {} \"{}\"", self.name.to_lowercase(), text);
+        let value = match text.strip_suffix('%').filter(|_| self.scale == Scale_SCALE_PCT) {
+            Some(percent) => percent.trim().parse::<f32>().map_err(|_| invalid())? / 100.0,
+            None => text.strip_suffix(self.unit).unwrap_or(text).trim().parse().map_err(|_| invalid())?,
+        };
+        if !(self.min..=self.max).contains(&value) {
+            return Err(format!("{} {} is outside {} to {}", self.name, text, self.write_value(self.min), self.write_value(self.max)));
+        }
+        Ok(self.clamp(value))
+    }
+
+    /// Formats a value for display, in Hz/kHz, ms/s, dB or percent as the scale suggests.
+    pub fn format(&self, value: f32) -> String {
+        if let Some((_, name)) = self.choices.iter().find(|(v, _)| *v as f32 == value) {
+            return name.to_string();
+        }
+        match self.scale {
+            Scale_SCALE_FreqExp if value >= 1000.0 => format!("{:.2} kHz", value / 1000.0),
+            Scale_SCALE_FreqExp => format!("{:.0} Hz", value),
+            Scale_SCALE_LogTime if value >= 1000.0 => format!("{:.2} s", value / 1000.0),
+            Scale_SCALE_LogTime => format!("{:.0} ms", value),
+            Scale_SCALE_DB if value <= self.min => "-inf dB".to_string(),
+            Scale_SCALE_DB => format!("{:+.0} dB", value),
+            Scale_SCALE_PCT => format!("{:.0}%", value * 100.0),
+            _ if self.kind == ValueKind::Float => format!("{:.2} {}", value, self.unit),
+            _ => format!("{} {}", value as i32, self.unit),
+        }
+    }
+
+    /// Parses typed input such as "1.2k", "350ms", "0.5 s", "-6dB", "-inf" or "40%". A number
+    /// without a unit is taken to be in the unit the value is displayed in.
+    pub fn parse(&self, text: &str) -> Option<f32> {
+        let text = text.trim().to_lowercase();
+        if let Some((value, _)) = self.choices.iter().find(|(_, name)| name.to_lowercase() == text) {
+            return Some(*value as f32);
+        }
+        if self.scale == Scale_SCALE_DB && text.trim_end_matches("db").trim() == "-inf" {
+            return Some(self.min);
+        }
+        let split = text.find(|c: char| !(c.is_ascii_digit() || matches!(c, '.' | '-' | '+'))).unwrap_or(text.len());
+        let (number, suffix) = text.split_at(split);
+        let number: f32 = number.parse().ok()?;
+        let factor = match (self.scale, suffix.trim()) {
+            (Scale_SCALE_FreqExp, "" | "hz") => 1.0,
+            (Scale_SCALE_FreqExp, "k" | "khz") => 1000.0,
+            (Scale_SCALE_LogTime, "" | "ms") => 1.0,
+            (Scale_SCALE_LogTime, "s") => 1000.0,
+            (Scale_SCALE_PCT, "" | "%") => 0.01,
+            (_, "") => 1.0,
+            (_, unit) if unit == self.unit.to_lowercase() => 1.0,
+            _ => return None,
+        };
+        Some(self.clamp(number * factor))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The ends, the default and a few points between, for every parameter.
+    fn samples(desc: &ParamDesc) -> Vec<f32> {
+        let mut values = vec![desc.min, desc.max, desc.default];
+        values.extend([0.1, 0.33, 0.5, 0.77, 0.95].map(|x| desc.denormalise(x)));
+        values
+    }
+
+    /// How far a displayed value can be from the real one, given the digits `format` shows.
+    fn display_precision(desc: &ParamDesc, value: f32) -> f32 {
+        match desc.scale {
+            Scale_SCALE_FreqExp | Scale_SCALE_LogTime if value >= 1000.0 => 5.0,
+            Scale_SCALE_FreqExp | Scale_SCALE_LogTime => 0.5,
+            Scale_SCALE_PCT => 0.005,
+            _ if desc.kind == ValueKind::Float => 0.005,
+            _ => 0.0,
+        }
+    }
+
+    #[test]
+    fn formatted_values_parse_back() {
+        for desc in &PARAMS {
+            for value in samples(desc) {
+                let text = desc.format(value);
+                let parsed = desc.parse(&text).unwrap_or_else(|| panic!("{} failed to parse {:?}", desc.full_name(), text));
+                let precision = display_precision(desc, value);
+                assert!((parsed - value).abs() <= precision + f32::EPSILON * value.abs(), "{}: {} became {:?} then {}", desc.full_name(), value, text, parsed);
+            }
+        }
+    }
+
+    #[test]
+    fn written_values_read_back_exactly() {
+        for desc in &PARAMS {
+            for value in samples(desc) {
+                assert_eq!(desc.read_value(&desc.write_value(value)), Ok(value), "{}", desc.full_name());
+            }
+        }
+    }
+
+    #[test]
+    fn parse_understands_units() {
+        let cutoff = param(Section_Filter, ParameterType_Cutoff);
+        assert_eq!(cutoff.parse("1.2k"), Some(1200.0));
+        assert_eq!(cutoff.parse("350 Hz"), Some(350.0));
+        assert_eq!(cutoff.parse("100 kHz"), Some(20000.0));
+        let attack = param(Section_Amp, ParameterType_Attack);
+        assert_eq!(attack.parse("350ms"), Some(350.0));
+        assert_eq!(attack.parse("0.5 s"), Some(500.0));
+        let gain = param(Section_Amp, ParameterType_Gain);
+        assert_eq!(gain.parse("-6dB"), Some(-6.0));
+        assert_eq!(gain.parse("-inf"), Some(gain.min));
+        let sustain = param(Section_Amp, ParameterType_Sustain);
+        assert_eq!(sustain.parse("50%"), Some(0.5));
+        assert_eq!(param(Section_Osc1, ParameterType_Waveform).parse("sqr"), Some(WaveformEnum_SQR as f32));
+        assert_eq!(attack.parse("fast"), None);
+        assert_eq!(attack.parse("3 Hz"), None);
+    }
+
+    #[test]
+    fn read_value_rejects_out_of_range() {
+        let attack = param(Section_Amp, ParameterType_Attack);
+        assert!(attack.read_value("5000 ms").is_err());
+        assert!(param(Section_Osc1, ParameterType_Waveform).read_value("Tri").is_err());
+    }
+}
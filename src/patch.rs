@@ -0,0 +1,423 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use egui_file_dialog::{DialogMode, FileDialog};
+use crate::app::{Message, PatchUI};
+use crate::binary::{decode_patch, encode_patch, is_binary_path, PATCH_RECORD_SIZE};
+use crate::bindings::{Patch, Section, Section_Global, Section_N_SECTIONS, Section_Osc1, SynthMessage};
+use crate::params::{self, PARAMS};
+use crate::sysex::{decode_dumps, encode_dump, is_sysex, is_sysex_path, DumpSlot};
+
+/// Bump when the meaning of a patch file changes; files from newer versions are refused.
+/// Version 2 added the library details.
+pub const PATCH_FORMAT_VERSION: u32 = 2;
+/// Ratings run from 1 to this, with 0 meaning unrated.
+pub const MAX_RATING: u8 = 5;
+const PATCH_HEADER: &str = "# BassSynth patch";
+
+/// The messages that turn the synth's `from` patch into `to`: one for each parameter that
+/// differs, in registry order, and nothing for those that are already equal. Values are
+/// compared as they would be sent, after `ParamDesc::clamp`, so a value out of range equals the
+/// end of the range it is sent as and a value that isn't a number equals the default.
+pub fn diff_patches(from: &Patch, to: &Patch) -> Vec<SynthMessage> {
+    let (from, to) = (PatchUI::from(*from), PatchUI::from(*to));
+    PARAMS.iter()
+        .filter_map(|desc| {
+            let value = desc.clamp(to.get(desc.section, desc.parameter)?);
+            (desc.clamp(from.get(desc.section, desc.parameter)?) != value).then(|| desc.command(value).into())
+        })
+        .collect()
+}
+
+/// Writes a patch as text: a header with the format version and the patch name, then one
+/// `[Section]` per section with a `Parameter = value` line for each parameter, e.g.
+///
+/// ```text
+/// [Filter]
+/// Mode = LP
+/// Cutoff = 1200 Hz
+/// ```
+pub fn patch_to_text(file: &PatchFile) -> String {
+    let mut text = format!("{}\nversion = {}\n", PATCH_HEADER, PATCH_FORMAT_VERSION);
+    let info = &file.info;
+    if !info.author.is_empty() {
+        text += &format!("author = {}\n", info.author);
+    }
+    if !info.tags.is_empty() {
+        text += &format!("tags = {}\n", info.tags.join(", "));
+    }
+    if info.rating > 0 {
+        text += &format!("rating = {}\n", info.rating);
+    }
+    if !info.notes.is_empty() {
+        text += &format!("notes = {}\n", escape_line(&info.notes));
+    }
+    write_patch_body(&mut text, &file.name, &file.patch);
+    text
+}
+
+/// Keeps text on one line by escaping newlines and backslashes.
+fn escape_line(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape_line(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                unescaped.push('\n');
+                chars.next();
+            }
+            ('\\', Some('\\')) => {
+                unescaped.push('\\');
+                chars.next();
+            }
+            _ => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+/// Details for finding a patch in the library, kept in its file alongside the name.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PatchInfo {
+    pub author: String,
+    pub tags: Vec<String>,
+    /// From 1 to `MAX_RATING`, or 0 if unrated.
+    pub rating: u8,
+    pub notes: String,
+}
+
+impl PatchInfo {
+    /// Reads comma-separated tags, dropping empty and repeated ones.
+    pub fn set_tags(&mut self, tags: &str) {
+        self.tags.clear();
+        for tag in tags.split(',').map(str::trim).filter(|tag| !tag.is_empty()) {
+            if !self.tags.iter().any(|t| t.eq_ignore_ascii_case(tag)) {
+                self.tags.push(tag.to_string());
+            }
+        }
+    }
+}
+
+/// A patch as kept in a file.
+#[derive(Clone)]
+pub struct PatchFile {
+    pub name: String,
+    pub info: PatchInfo,
+    pub patch: Patch,
+}
+
+impl PatchFile {
+    /// A patch from a format without a name or details, named after its file.
+    fn unnamed(path: &Path, patch: Patch) -> Self {
+        let name = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+        PatchFile { name, info: PatchInfo::default(), patch }
+    }
+}
+
+/// Writes a patch's name and sections, the part of the format shared with bank files.
+pub fn write_patch_body(text: &mut String, name: &str, patch: &Patch) {
+    let patch = PatchUI::from(*patch);
+    *text += &format!("name = {}\n", name);
+    let mut section = None;
+    for desc in PARAMS.iter().filter(|d| d.section != Section_Global) {
+        let Some(value) = patch.get(desc.section, desc.parameter) else {
+            continue;
+        };
+        if section != Some(desc.section) {
+            section = Some(desc.section);
+            *text += &format!("\n[{}]\n", params::section_name(desc.section));
+        }
+        *text += &format!("{} = {}\n", desc.name, desc.write_value(value));
+    }
+}
+
+/// The lines of a file that hold something, trimmed and numbered from one.
+pub fn content_lines(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+}
+
+/// Splits a `key = value` line.
+pub fn key_value(line: &str) -> Result<(&str, &str), String> {
+    let (key, value) = line.split_once('=')
+        .ok_or_else(|| format!("Expected \"name = value\", found \"{}\"", line))?;
+    Ok((key.trim(), value.trim()))
+}
+
+pub fn read_version(value: &str) -> Result<u32, String> {
+    let version: u32 = value.parse().map_err(|_| format!("Invalid version \"{}\"", value))?;
+    if version > PATCH_FORMAT_VERSION {
+        return Err(format!("Format version {} is newer than this app supports ({})", version, PATCH_FORMAT_VERSION));
+    }
+    Ok(version)
+}
+
+/// Reads the lines written by `write_patch_body`. Parameters that are never set keep their
+/// default values.
+#[derive(Default)]
+pub struct PatchReader {
+    patch: PatchUI,
+    name: String,
+    info: PatchInfo,
+    section: Option<Section>,
+}
+
+impl PatchReader {
+    /// Reads one line. Returns false for a setting before the first section that isn't the
+    /// patch's, which the file it is part of may know about.
+    pub fn read_line(&mut self, line: &str) -> Result<bool, String> {
+        if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            let found = (Section_Osc1..Section_N_SECTIONS).find(|s| params::section_name(*s).eq_ignore_ascii_case(header.trim()));
+            self.section = Some(found.ok_or_else(|| format!("Unknown section [{}]", header))?);
+            return Ok(true);
+        }
+        let (key, value) = key_value(line)?;
+        match self.section {
+            None => match key {
+                "name" => self.name = value.to_string(),
+                "author" => self.info.author = value.to_string(),
+                "tags" => self.info.set_tags(value),
+                "rating" => {
+                    self.info.rating = value.parse().ok()
+                        .filter(|rating| *rating <= MAX_RATING)
+                        .ok_or_else(|| format!("Invalid rating \"{}\"", value))?;
+                }
+                "notes" => self.info.notes = unescape_line(value),
+                _ => return Ok(false),
+            },
+            Some(section) => {
+                let desc = PARAMS.iter()
+                    .find(|d| d.section == section && d.name.eq_ignore_ascii_case(key))
+                    .ok_or_else(|| format!("Unknown parameter \"{}\" in [{}]", key, params::section_name(section)))?;
+                self.patch.set(section, desc.parameter, desc.read_value(value)?);
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn finish(self) -> PatchFile {
+        PatchFile { name: self.name, info: self.info, patch: Patch::from(&self.patch) }
+    }
+}
+
+/// Reads a patch written by `patch_to_text`.
+pub fn patch_from_text(text: &str) -> Result<PatchFile, String> {
+    let mut reader = PatchReader::default();
+    let mut version = None;
+    for (number, line) in content_lines(text) {
+        let err = |e: String| format!("Line {}: {}", number, e);
+        if !reader.read_line(line).map_err(err)? {
+            match key_value(line).map_err(err)? {
+                ("version", value) => version = Some(read_version(value).map_err(err)?),
+                (key, _) => return Err(err(format!("Unknown setting \"{}\"", key))),
+            }
+        }
+    }
+    if version.is_none() {
+        return Err("Not a patch file: the format version is missing".to_string());
+    }
+    Ok(reader.finish())
+}
+
+/// Loads a patch file, a raw record in the firmware's binary format or a SysEx dump. The last
+/// two are named after the file since they carry no name.
+pub fn load_patch(path: &Path) -> Result<PatchFile, String> {
+    let bytes = std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    patch_from_bytes(path, bytes)
+}
+
+/// Whether a file is in the text format, the only one that keeps a patch's details.
+pub fn is_patch_text(bytes: &[u8]) -> bool {
+    bytes.starts_with(PATCH_HEADER.as_bytes())
+}
+
+/// Reads the contents of a file in any of the formats `load_patch` accepts.
+pub fn patch_from_bytes(path: &Path, bytes: Vec<u8>) -> Result<PatchFile, String> {
+    let loaded = if is_sysex(&bytes) {
+        decode_dumps(&bytes).and_then(|dumps| match dumps[..] {
+            [(_, patch)] => Ok(PatchFile::unnamed(path, patch)),
+            _ => Err(format!("The file holds {} patches: open it as a bank", dumps.len())),
+        })
+    } else if bytes.len() == PATCH_RECORD_SIZE && !is_patch_text(&bytes) {
+        decode_patch(&bytes).map(|patch| PatchFile::unnamed(path, patch))
+    } else {
+        String::from_utf8(bytes)
+            .map_err(|_| "Not a patch file".to_string())
+            .and_then(|text| patch_from_text(&text))
+    };
+    loaded.map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Saves a patch file, or a raw record or SysEx dump if the path has the binary or SysEx
+/// extension. Those keep only the patch itself.
+pub fn save_patch(path: &Path, file: &PatchFile) -> Result<(), String> {
+    let bytes = if is_binary_path(path) {
+        encode_patch(&file.patch).to_vec()
+    } else if is_sysex_path(path) {
+        encode_dump(DumpSlot::EditBuffer, &file.patch)?
+    } else {
+        patch_to_text(file).into_bytes()
+    };
+    std::fs::write(path, bytes).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+/// Open and Save for single patch files.
+pub struct PatchFiles {
+    dialog: FileDialog,
+    pub path: Option<PathBuf>,
+    pub status: Option<String>,
+    pub error: Option<String>,
+}
+
+impl Default for PatchFiles {
+    fn default() -> Self {
+        PatchFiles { dialog: FileDialog::new(), path: None, status: None, error: None }
+    }
+}
+
+impl PatchFiles {
+    pub fn open(&mut self) {
+        self.dialog.select_file();
+    }
+
+    pub fn save(&mut self) {
+        self.dialog.save_file();
+    }
+}
+
+/// Runs the file dialog and loads or saves the patch once a file is picked. Loaded patches are
+/// sent to the synth.
+pub fn handle_patch_files(files: &mut PatchFiles, patch: &mut PatchUI, name: &mut String, info: &mut PatchInfo, sender: &Sender<Message>, ctx: &egui::Context) {
+    files.dialog.update(ctx);
+    if let Some(path) = files.dialog.take_selected() {
+        let result = if files.dialog.mode() == DialogMode::SaveFile {
+            let file = PatchFile { name: name.clone(), info: info.clone(), patch: Patch::from(&*patch) };
+            save_patch(&path, &file).map(|()| format!("Saved {}", path.display()))
+        } else {
+            load_patch(&path).map(|loaded| {
+                for msg in patch.load(loaded.patch) {
+                    sender.send(msg).unwrap();
+                }
+                *name = loaded.name;
+                *info = loaded.info;
+                format!("Loaded {}", path.display())
+            })
+        };
+        match result {
+            Ok(status) => {
+                files.status = Some(status);
+                files.path = Some(path);
+            }
+            Err(e) => files.error = Some(e),
+        }
+    }
+
+    let mut dismissed = false;
+    if let Some(error) = &files.error {
+        egui::Window::new("Patch file error").collapsible(false).resizable(false).show(ctx, |ui| {
+            ui.label(error.as_str());
+            dismissed = ui.button("OK").clicked();
+        });
+    }
+    if dismissed {
+        files.error = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bindings::{ParameterType_Cutoff, Section_Filter};
+    use crate::synth::{Oscillator, ParamValue, SynthCommand};
+    use crate::test_util::{sample_patch, sample_patches};
+
+    fn commands(messages: Vec<SynthMessage>) -> Vec<SynthCommand> {
+        messages.into_iter().map(|message| SynthCommand::try_from(message).unwrap()).collect()
+    }
+
+    #[test]
+    fn diff_sends_only_what_changed() {
+        let from = Patch::from(&PatchUI::default());
+        assert!(diff_patches(&from, &from).is_empty());
+        let to = Patch { Filter_Cutoff: 1200.0, Osc2_Coarse: -12, ..from };
+        assert_eq!(commands(diff_patches(&from, &to)), vec![
+            SynthCommand::Coarse(Oscillator::Osc2, -12),
+            SynthCommand::Cutoff(1200.0),
+        ]);
+    }
+
+    #[test]
+    fn diff_compares_values_as_sent() {
+        let from = Patch::from(&PatchUI::default());
+        let max_cutoff = params::param(Section_Filter, ParameterType_Cutoff).max;
+        // Both are sent as the top of the range, so there is nothing to change.
+        let above = Patch { Filter_Cutoff: max_cutoff * 2.0, ..from };
+        let at_max = Patch { Filter_Cutoff: max_cutoff, ..from };
+        assert!(diff_patches(&above, &at_max).is_empty());
+        assert!(diff_patches(&at_max, &above).is_empty());
+        let lower = Patch { Filter_Cutoff: 1000.0, ..from };
+        assert_eq!(commands(diff_patches(&lower, &above)), vec![SynthCommand::Cutoff(max_cutoff)]);
+
+        // Not a number is sent as the default, and never differs from itself.
+        let nan = Patch { Amp_Attack: f32::NAN, ..from };
+        assert!(diff_patches(&nan, &nan).is_empty());
+        assert!(diff_patches(&from, &nan).is_empty());
+        let slow = Patch { Amp_Attack: 1000.0, ..from };
+        let sent = commands(diff_patches(&slow, &nan));
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].value(), ParamValue::Float(from.Amp_Attack));
+    }
+
+    #[test]
+    fn patch_text_round_trips() {
+        let info = PatchInfo {
+            author: "A. Author".to_string(),
+            tags: vec!["acid".to_string(), "sub bass".to_string()],
+            rating: MAX_RATING,
+            notes: "Two lines\nwith a \\ backslash".to_string(),
+        };
+        for patch in sample_patches() {
+            for info in [PatchInfo::default(), info.clone()] {
+                let file = PatchFile { name: "Squelch = 303".to_string(), info, patch };
+                let read = patch_from_text(&patch_to_text(&file)).unwrap();
+                assert_eq!(read.name, file.name);
+                assert_eq!(read.info, file.info);
+                assert_eq!(encode_patch(&read.patch), encode_patch(&file.patch));
+            }
+        }
+    }
+
+    #[test]
+    fn notes_keep_newlines_and_backslashes() {
+        for notes in ["", "one line", "two\nlines", "back\\slash\\n", "\\", "trailing\\"] {
+            assert_eq!(unescape_line(&escape_line(notes)), notes);
+            assert!(!escape_line(notes).contains('\n'));
+        }
+    }
+
+    #[test]
+    fn version_1_patches_load_without_details() {
+        let file = PatchFile { name: "Old".to_string(), info: PatchInfo::default(), patch: sample_patch(0.3) };
+        let text = patch_to_text(&file).replace(&format!("version = {}", PATCH_FORMAT_VERSION), "version = 1");
+        let read = patch_from_text(&text).unwrap();
+        assert_eq!(read.info, PatchInfo::default());
+        assert_eq!(encode_patch(&read.patch), encode_patch(&file.patch));
+    }
+
+    #[test]
+    fn bad_patch_text_is_rejected() {
+        let text = patch_to_text(&PatchFile { name: "Bad".to_string(), info: PatchInfo::default(), patch: sample_patch(0.6) });
+        let newer = format!("version = {}", PATCH_FORMAT_VERSION + 1);
+        assert!(patch_from_text(&text.replace(&format!("version = {}", PATCH_FORMAT_VERSION), &newer)).is_err());
+        assert!(patch_from_text(&text.replace(&format!("version = {}\n", PATCH_FORMAT_VERSION), "")).is_err());
+        assert!(patch_from_text(&format!("{}Wobble = 3\n", text)).is_err());
+        assert!(patch_from_text(&format!("{}\n[Filter]\nCutoff = 50000 Hz\n", text)).is_err());
+        assert!(patch_from_text(&format!("{}\n[Reverb]\n", text)).is_err());
+        assert!(patch_from_text(&text.replace("name = Bad", "rating = 9")).is_err());
+    }
+}
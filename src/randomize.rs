@@ -0,0 +1,145 @@
+use std::collections::HashSet;
+use std::sync::mpsc::Sender;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::app::{Message, PatchUI};
+use crate::bindings::{ParameterType, ParameterType_Coarse, ParameterType_Fine, ParameterType_Gain, ParameterType_Resonance, Section, Section_Amp, Section_Global, Section_N_SECTIONS, Section_Osc1};
+use crate::params::{self, ParamDesc, PARAMS};
+
+/// Small, fast generator whose output for a seed never changes, so seeds stay reproducible.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `0.0..1.0`.
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+/// Draws a value for a parameter. Most are uniform in control position, which is already log
+/// for cutoff and times and follows loudness for gains; the rest are kept musical.
+fn sample(desc: &ParamDesc, rng: &mut SplitMix64) -> f32 {
+    let u = rng.next_f32();
+    match (desc.section, desc.parameter) {
+        // Whole octaves only, so the oscillators stay in tune with each other.
+        (_, ParameterType_Coarse) => {
+            let octaves = (desc.max / 12.0) as i32;
+            let octave = (u * (2 * octaves + 1) as f32) as i32 - octaves;
+            (octave * 12) as f32
+        }
+        // A little detune either side of centre, more often small than large.
+        (_, ParameterType_Fine) => {
+            let spread = (u + rng.next_f32() - 1.0) * 0.2;
+            desc.denormalise(0.5 + spread)
+        }
+        (Section_Amp, ParameterType_Gain) => desc.denormalise(0.8 + 0.2 * u),
+        // Oscillators can be quiet but never silent.
+        (_, ParameterType_Gain) => desc.denormalise(0.5 + 0.5 * u),
+        // Mostly low, as high resonance self-oscillates.
+        (_, ParameterType_Resonance) => desc.denormalise(u * u),
+        _ => desc.denormalise(u),
+    }
+}
+
+pub struct Randomizer {
+    pub open: bool,
+    pub seed: u32,
+    /// How far each section moves from the current patch, from 0 (not at all) to 1, indexed by
+    /// section.
+    pub amounts: [f32; Section_N_SECTIONS as usize],
+    /// Parameters that are never changed.
+    pub locks: HashSet<(Section, ParameterType)>,
+}
+
+impl Default for Randomizer {
+    fn default() -> Self {
+        Randomizer {
+            open: false,
+            seed: 0,
+            amounts: [1.0; Section_N_SECTIONS as usize],
+            locks: HashSet::from([(Section_Amp, ParameterType_Gain)]),
+        }
+    }
+}
+
+impl Randomizer {
+    /// Picks a new seed and randomises the patch with it.
+    pub fn randomize(&mut self, patch: &mut PatchUI, sender: &Sender<Message>) {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+        self.seed = SplitMix64(nanos ^ self.seed as u64).next_u64() as u32;
+        self.apply(patch, sender);
+    }
+
+    /// Randomises the patch with the current seed. The same seed and settings always turn the
+    /// same patch into the same result.
+    pub fn apply(&self, patch: &mut PatchUI, sender: &Sender<Message>) {
+        let mut rng = SplitMix64(self.seed as u64);
+        for desc in PARAMS.iter().filter(|d| d.section != Section_Global) {
+            // Draw for every parameter, locked or not, so a lock doesn't change the others.
+            let sampled = sample(desc, &mut rng);
+            let chance = rng.next_f32();
+            let amount = self.amounts[desc.section as usize];
+            if self.locks.contains(&(desc.section, desc.parameter)) || amount <= 0.0 {
+                continue;
+            }
+            let Some(current) = patch.get(desc.section, desc.parameter) else {
+                continue;
+            };
+            let value = if desc.is_stepped() || desc.parameter == ParameterType_Coarse {
+                if chance < amount { sampled } else { current }
+            } else {
+                let x = desc.normalise(current);
+                desc.denormalise(x + amount * (desc.normalise(sampled) - x))
+            };
+            if let Some(msg) = patch.set(desc.section, desc.parameter, value) {
+                sender.send(msg).unwrap();
+            }
+        }
+    }
+}
+
+pub fn draw_randomizer(randomizer: &mut Randomizer, patch: &mut PatchUI, sender: &Sender<Message>, ctx: &egui::Context) {
+    let mut open = randomizer.open;
+    egui::Window::new("Randomize").open(&mut open).show(ctx, |ui| {
+        ui.horizontal(|ui| {
+            if ui.button("Randomize").clicked() {
+                randomizer.randomize(patch, sender);
+            }
+            ui.label("Seed");
+            ui.add(egui::DragValue::new(&mut randomizer.seed));
+            if ui.button("Apply seed").on_hover_text("The same seed on the same patch gives the same result").clicked() {
+                randomizer.apply(patch, sender);
+            }
+        });
+        ui.separator();
+        for section in Section_Osc1..Section_N_SECTIONS {
+            egui::CollapsingHeader::new(params::section_name(section)).show(ui, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label("Amount");
+                    ui.add(egui::Slider::new(&mut randomizer.amounts[section as usize], 0.0..=1.0).custom_formatter(|x, _| format!("{:.0}%", x * 100.0)));
+                });
+                ui.horizontal_wrapped(|ui| {
+                    for desc in PARAMS.iter().filter(|d| d.section == section) {
+                        let key = (desc.section, desc.parameter);
+                        let mut locked = randomizer.locks.contains(&key);
+                        if ui.checkbox(&mut locked, format!("Lock {}", desc.name)).changed() {
+                            if locked {
+                                randomizer.locks.insert(key);
+                            } else {
+                                randomizer.locks.remove(&key);
+                            }
+                        }
+                    }
+                });
+            });
+        }
+    });
+    randomizer.open = open;
+}
@@ -0,0 +1,186 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use egui_file_dialog::FileDialog;
+use midly::num::{u15, u24, u28, u4, u7};
+use midly::{Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+use crate::clock::Clock;
+use crate::midi_file::NoteEvent;
+use crate::sequencer::{Pattern, SequencerState, Step, MAX_STEPS, PATTERN_SLOTS};
+
+const TICKS_PER_BEAT: u16 = 480;
+const STEPS_PER_BEAT: f64 = 4.0;
+const ACCENT_THRESHOLD: u8 = 110;
+
+/// Every note sent to the synth while recording, timed from when recording started.
+#[derive(Default)]
+pub struct Recorder {
+    pub recording: bool,
+    start: Option<Instant>,
+    pub events: Vec<(Duration, NoteEvent)>,
+}
+
+impl Recorder {
+    pub fn start(&mut self) {
+        self.events.clear();
+        self.start = Some(Instant::now());
+        self.recording = true;
+    }
+
+    pub fn stop(&mut self) {
+        self.recording = false;
+    }
+
+    /// Called by the server thread for every note it sends, with when the note was played.
+    /// Notes from different sources can arrive out of order, so each is put in its place.
+    pub fn record(&mut self, event: NoteEvent, played: Instant) {
+        let Some(time) = self.start.filter(|_| self.recording).and_then(|start| played.checked_duration_since(start)) else {
+            return;
+        };
+        let index = self.events.partition_point(|(t, _)| *t <= time);
+        self.events.insert(index, (time, event));
+    }
+
+    pub fn length(&self) -> Duration {
+        self.events.last().map_or(Duration::ZERO, |(time, _)| *time)
+    }
+
+    pub fn to_smf(&self, tempo: f32) -> Smf<'static> {
+        let ticks_per_second = tempo as f64 / 60.0 * TICKS_PER_BEAT as f64;
+        let mut track = vec![TrackEvent {
+            delta: u28::new(0),
+            kind: TrackEventKind::Meta(MetaMessage::Tempo(u24::new((60_000_000.0 / tempo as f64) as u32))),
+        }];
+        let mut last_tick = 0;
+        for (time, event) in &self.events {
+            let tick = (time.as_secs_f64() * ticks_per_second).round() as u32;
+            let message = match *event {
+                NoteEvent::On(note, vel) => MidiMessage::NoteOn { key: u7::new(note), vel: u7::new(vel) },
+                NoteEvent::Off(note) => MidiMessage::NoteOff { key: u7::new(note), vel: u7::new(0) },
+            };
+            track.push(TrackEvent {
+                delta: u28::new(tick - last_tick),
+                kind: TrackEventKind::Midi { channel: u4::new(0), message },
+            });
+            last_tick = tick;
+        }
+        track.push(TrackEvent { delta: u28::new(0), kind: TrackEventKind::Meta(MetaMessage::EndOfTrack) });
+
+        let mut smf = Smf::new(Header::new(Format::SingleTrack, Timing::Metrical(u15::new(TICKS_PER_BEAT))));
+        smf.tracks.push(track);
+        smf
+    }
+
+    /// Quantises the recording to sixteenth notes at `tempo`. Loud notes become accents and
+    /// notes held into the next step become slides.
+    pub fn to_pattern(&self, tempo: f32, root: u8) -> Pattern {
+        let steps_per_second = tempo as f64 / 60.0 * STEPS_PER_BEAT;
+        let step_of = |time: &Duration| (time.as_secs_f64() * steps_per_second).round() as usize;
+        let total_steps = step_of(&self.length()).max(1);
+        let mut pattern = Pattern {
+            steps: [Step { rest: true, ..Step::default() }; MAX_STEPS],
+            length: if total_steps > 16 { 32 } else { 16 },
+        };
+
+        for (index, (time, event)) in self.events.iter().enumerate() {
+            let NoteEvent::On(note, vel) = *event else {
+                continue;
+            };
+            let step = step_of(time);
+            if step >= pattern.length {
+                break;
+            }
+            let released = self.events[index..].iter()
+                .find(|(_, e)| *e == NoteEvent::Off(note))
+                .map_or(total_steps, |(t, _)| step_of(t));
+            let interval = note as i32 - root as i32;
+            pattern.steps[step] = Step {
+                note: interval.rem_euclid(12) as u8,
+                octave: interval.div_euclid(12).clamp(-1, 1) as i8,
+                accent: vel >= ACCENT_THRESHOLD,
+                slide: released > step + 1,
+                rest: false,
+            };
+        }
+        pattern
+    }
+}
+
+pub struct RecorderWindow {
+    pub shared: Arc<Mutex<Recorder>>,
+    pub open: bool,
+    dialog: FileDialog,
+    pattern_slot: usize,
+    status: Option<String>,
+}
+
+impl RecorderWindow {
+    pub fn new(shared: Arc<Mutex<Recorder>>) -> Self {
+        RecorderWindow { shared, open: false, dialog: FileDialog::new(), pattern_slot: 0, status: None }
+    }
+}
+
+fn save_smf(recorder: &Recorder, tempo: f32, path: &Path) -> Result<(), String> {
+    recorder.to_smf(tempo)
+        .save(path)
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+pub fn draw_recorder(window: &mut RecorderWindow, sequencer: &Mutex<SequencerState>, clock: &Mutex<Clock>, ctx: &egui::Context) {
+    window.dialog.update(ctx);
+    if let Some(path) = window.dialog.take_selected() {
+        let tempo = clock.lock().unwrap().tempo;
+        window.status = Some(match save_smf(&window.shared.lock().unwrap(), tempo, &path) {
+            Ok(()) => format!("Saved {}", path.display()),
+            Err(e) => e,
+        });
+    }
+
+    let RecorderWindow { shared, open, dialog, pattern_slot, status } = window;
+    egui::Window::new("Recorder").open(open).show(ctx, |ui| {
+        let mut recorder = shared.lock().unwrap();
+        ui.horizontal(|ui| {
+            if recorder.recording {
+                if ui.button("Stop").clicked() {
+                    recorder.stop();
+                }
+                ctx.request_repaint_after(Duration::from_millis(100));
+            } else if ui.button("Record").clicked() {
+                recorder.start();
+                *status = None;
+            }
+            let notes = recorder.events.iter().filter(|(_, e)| matches!(e, NoteEvent::On(..))).count();
+            ui.label(format!("{} notes, {:.1} s", notes, recorder.length().as_secs_f32()));
+        });
+        ui.add_enabled_ui(!recorder.recording && !recorder.events.is_empty(), |ui| {
+            ui.horizontal(|ui| {
+                if ui.button("Export MIDI file").clicked() {
+                    dialog.save_file();
+                }
+                if ui.button("Clear").clicked() {
+                    recorder.events.clear();
+                }
+            });
+            ui.horizontal(|ui| {
+                if ui.button("To pattern").clicked() {
+                    let tempo = clock.lock().unwrap().tempo;
+                    let mut sequencer = sequencer.lock().unwrap();
+                    let pattern = recorder.to_pattern(tempo, sequencer.root);
+                    sequencer.patterns[*pattern_slot] = pattern;
+                    *status = Some(format!("Copied to pattern {}", *pattern_slot + 1));
+                }
+                egui::ComboBox::from_id_source("Recorder pattern slot")
+                    .selected_text(format!("{}", *pattern_slot + 1))
+                    .width(40.0)
+                    .show_ui(ui, |ui| {
+                        for slot in 0..PATTERN_SLOTS {
+                            ui.selectable_value(pattern_slot, slot, format!("{}", slot + 1));
+                        }
+                    });
+            });
+        });
+        if let Some(status) = status {
+            ui.label(status.as_str());
+        }
+    });
+}
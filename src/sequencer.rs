@@ -0,0 +1,368 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use egui::Ui;
+use crate::app::{Message, Note};
+use crate::clock::{wait_until, Clock, ClockSource, MAX_WAIT};
+
+pub const MAX_STEPS: usize = 32;
+pub const PATTERN_SLOTS: usize = 8;
+const NOTE_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+const GATE: f64 = 0.5;
+const VELOCITY: u8 = 90;
+const ACCENT_VELOCITY: u8 = 127;
+const IDLE_POLL: Duration = Duration::from_millis(5);
+
+/// One step of a 303-style pattern. A slide step holds its note into the next one, which
+/// is played legato (or tied, if it is the same note).
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Step {
+    /// Semitone above the sequencer's root note.
+    pub note: u8,
+    /// Octave transpose, from -1 to +1.
+    pub octave: i8,
+    pub accent: bool,
+    pub slide: bool,
+    pub rest: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pattern {
+    pub steps: [Step; MAX_STEPS],
+    pub length: usize,
+}
+
+impl Default for Pattern {
+    fn default() -> Self {
+        Pattern {
+            steps: [Step::default(); MAX_STEPS],
+            length: 16,
+        }
+    }
+}
+
+/// Serialises a step as its note name, an octave sign and flags, e.g. `D#+as`, or `.` for a rest.
+impl Display for Step {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.rest {
+            return write!(f, ".");
+        }
+        write!(f, "{}", NOTE_NAMES[self.note as usize % 12])?;
+        match self.octave {
+            1 => write!(f, "+")?,
+            -1 => write!(f, "-")?,
+            _ => {}
+        }
+        if self.accent {
+            write!(f, "a")?;
+        }
+        if self.slide {
+            write!(f, "s")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Step {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "." {
+            return Ok(Step { rest: true, ..Step::default() });
+        }
+        let name_len = if s.get(1..2) == Some("#") { 2 } else { 1 };
+        let name = s.get(..name_len).ok_or_else(|| format!("Invalid step \"{}\"", s))?;
+        let note = NOTE_NAMES.iter()
+            .position(|n| *n == name)
+            .ok_or_else(|| format!("Invalid note \"{}\"", name))?;
+        let mut step = Step { note: note as u8, ..Step::default() };
+        for flag in s[name_len..].chars() {
+            match flag {
+                '+' => step.octave = 1,
+                '-' => step.octave = -1,
+                'a' => step.accent = true,
+                's' => step.slide = true,
+                _ => return Err(format!("Invalid step flag '{}' in \"{}\"", flag, s)),
+            }
+        }
+        Ok(step)
+    }
+}
+
+/// Serialises a pattern as its length followed by every step, e.g. `16: C . D#+as ...`.
+impl Display for Pattern {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:", self.length)?;
+        for step in &self.steps[..self.length] {
+            write!(f, " {}", step)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (length, steps) = s.split_once(':').ok_or_else(|| format!("Missing pattern length in \"{}\"", s))?;
+        let length: usize = length.trim().parse().map_err(|e| format!("Invalid pattern length: {}", e))?;
+        if !(1..=MAX_STEPS).contains(&length) {
+            return Err(format!("Pattern length {} is out of range", length));
+        }
+        let mut pattern = Pattern { length, ..Pattern::default() };
+        for (index, step) in steps.split_whitespace().enumerate().take(MAX_STEPS) {
+            pattern.steps[index] = step.parse()?;
+        }
+        Ok(pattern)
+    }
+}
+
+/// One pattern per line, as stored in the app's settings.
+pub fn patterns_to_string(patterns: &[Pattern]) -> String {
+    patterns.iter().map(|p| format!("{}\n", p)).collect()
+}
+
+pub fn patterns_from_string(s: &str) -> Result<Vec<Pattern>, String> {
+    let mut patterns: Vec<Pattern> = s.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(str::parse)
+        .collect::<Result<_, _>>()?;
+    patterns.resize(PATTERN_SLOTS, Pattern::default());
+    Ok(patterns)
+}
+
+/// State shared between the UI and the sequencer thread.
+pub struct SequencerState {
+    pub playing: bool,
+    /// Fraction of a step by which every second step is delayed.
+    pub swing: f32,
+    pub root: Note,
+    pub patterns: Vec<Pattern>,
+    pub current: usize,
+    /// Pattern to switch to when the current one reaches its end.
+    pub queued: Option<usize>,
+    /// Step being played, for display.
+    pub position: Option<usize>,
+}
+
+impl Default for SequencerState {
+    fn default() -> Self {
+        SequencerState {
+            playing: false,
+            swing: 0.0,
+            root: 36,
+            patterns: vec![Pattern::default(); PATTERN_SLOTS],
+            current: 0,
+            queued: None,
+            position: None,
+        }
+    }
+}
+
+impl SequencerState {
+    /// Length of a step in beats.
+    fn step_length(&self, index: usize) -> f64 {
+        let sixteenth = 0.25;
+        let swing = self.swing as f64 * sixteenth;
+        // Swing lengthens the first step of each pair and shortens the second by the same amount.
+        if index % 2 == 1 { sixteenth - swing } else { sixteenth + swing }
+    }
+
+    pub fn select_pattern(&mut self, index: usize) {
+        if self.playing {
+            self.queued = Some(index);
+        } else {
+            self.current = index;
+        }
+    }
+}
+
+/// Plays the shared patterns until the UI goes away. Steps are placed on the clock's beats
+/// rather than timed separately, so they follow MIDI clock pulses or the Link session and
+/// scheduling jitter does not accumulate into drift.
+pub fn run_sequencer(shared: Arc<Mutex<SequencerState>>, clock: Arc<Mutex<Clock>>, sender: Sender<Message>, ctx: egui::Context) {
+    let mut index = 0;
+    // Beats at which the next step starts and the sounding note ends.
+    let mut next_step: Option<f64> = None;
+    let mut sounding: Option<Note> = None;
+    let mut gate_off: Option<f64> = None;
+    let mut tied = false;
+    let mut was_running = false;
+
+    loop {
+        {
+            let mut state = shared.lock().unwrap();
+            let mut clock = clock.lock().unwrap();
+            if clock.running != was_running {
+                // Started or stopped by the external clock.
+                state.playing = clock.running;
+            } else if state.playing != clock.running && clock.source != ClockSource::MidiClock {
+                clock.set_running(state.playing);
+            }
+            was_running = clock.running;
+        }
+        if !shared.lock().unwrap().playing {
+            if let Some(note) = sounding.take() {
+                if sender.send(Message::NoteOff(note, Instant::now())).is_err() {
+                    return;
+                }
+            }
+            {
+                let mut state = shared.lock().unwrap();
+                state.position = None;
+                if let Some(queued) = state.queued.take() {
+                    state.current = queued;
+                }
+            }
+            if Arc::strong_count(&shared) == 1 {
+                return;
+            }
+            index = 0;
+            next_step = None;
+            gate_off = None;
+            tied = false;
+            thread::sleep(IDLE_POLL);
+            continue;
+        }
+
+        let (beat, step_beat, wake) = {
+            let mut clock = clock.lock().unwrap();
+            let beat = clock.advance();
+            // Join an external clock on its next beat, or start straight away.
+            let step_beat = *next_step.get_or_insert_with(|| if clock.is_external() { beat.ceil() } else { beat });
+            (beat, step_beat, clock.time_of(gate_off.map_or(step_beat, |g| g.min(step_beat))))
+        };
+        let now = Instant::now();
+
+        if gate_off.is_some_and(|g| g <= beat) {
+            gate_off = None;
+            if let Some(note) = sounding.take() {
+                if sender.send(Message::NoteOff(note, now)).is_err() {
+                    return;
+                }
+            }
+        }
+        if step_beat > beat {
+            wait_until(wake.min(now + MAX_WAIT));
+            continue;
+        }
+
+        let mut state = shared.lock().unwrap();
+        let pattern = &state.patterns[state.current];
+        let step = pattern.steps[index.min(pattern.length - 1)];
+        let length = state.step_length(index);
+        let mut messages = Vec::new();
+        if step.rest {
+            messages.extend(sounding.take().map(|note| Message::NoteOff(note, now)));
+            gate_off = None;
+        } else {
+            let note = (state.root as i32 + step.note as i32 + 12 * step.octave as i32).clamp(0, 127) as Note;
+            let previous = sounding.take();
+            if !(tied && previous == Some(note)) {
+                let velocity = if step.accent { ACCENT_VELOCITY } else { VELOCITY };
+                // Start the new note before releasing the old one so a slide plays legato.
+                messages.push(Message::NoteOn(note, velocity, now));
+                messages.extend(previous.map(|note| Message::NoteOff(note, now)));
+            }
+            sounding = Some(note);
+            gate_off = (!step.slide).then_some(step_beat + length * GATE);
+        }
+        tied = step.slide && !step.rest;
+
+        state.position = Some(index);
+        index += 1;
+        if index >= state.patterns[state.current].length {
+            index = 0;
+            if let Some(queued) = state.queued.take() {
+                state.current = queued;
+            }
+        }
+        drop(state);
+
+        next_step = Some(step_beat + length);
+        for msg in messages {
+            if sender.send(msg).is_err() {
+                return;
+            }
+        }
+        ctx.request_repaint();
+    }
+}
+
+fn draw_step(step: &mut Step, index: usize, playing: bool, ui: &mut Ui) {
+    ui.vertical(|ui| {
+        let label = format!("{}", index + 1);
+        if playing {
+            ui.colored_label(ui.visuals().selection.bg_fill, label);
+        } else {
+            ui.label(label);
+        }
+        let selected = if step.rest { "-".to_string() } else { NOTE_NAMES[step.note as usize].to_string() };
+        egui::ComboBox::from_id_source(("Step", index))
+            .width(36.0)
+            .selected_text(selected)
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut step.rest, true, "-");
+                for (note, name) in NOTE_NAMES.iter().enumerate() {
+                    if ui.selectable_label(!step.rest && step.note == note as u8, *name).clicked() {
+                        step.rest = false;
+                        step.note = note as u8;
+                    }
+                }
+            });
+        ui.add(egui::DragValue::new(&mut step.octave).range(-1..=1).prefix("O"));
+        ui.horizontal(|ui| {
+            ui.toggle_value(&mut step.accent, "A").on_hover_text("Accent");
+            ui.toggle_value(&mut step.slide, "S").on_hover_text("Slide / tie");
+        });
+    });
+}
+
+pub fn draw_sequencer(shared: &Mutex<SequencerState>, clock: &Mutex<Clock>, open: &mut bool, ctx: &egui::Context) {
+    egui::Window::new("Sequencer").open(open).show(ctx, |ui| {
+        let mut state = shared.lock().unwrap();
+        ui.horizontal(|ui| {
+            let label = if state.playing { "Stop" } else { "Play" };
+            if ui.button(label).clicked() {
+                state.playing = !state.playing;
+            }
+            ui.add(egui::DragValue::new(&mut clock.lock().unwrap().tempo).range(40.0..=300.0).suffix(" bpm"));
+            ui.label("Swing");
+            ui.add(egui::Slider::new(&mut state.swing, 0.0..=0.5).show_value(false));
+        });
+        ui.horizontal(|ui| {
+            ui.label("Pattern");
+            for slot in 0..PATTERN_SLOTS {
+                let label = if state.queued == Some(slot) { format!("{}*", slot + 1) } else { format!("{}", slot + 1) };
+                if ui.selectable_label(state.current == slot, label).clicked() {
+                    state.select_pattern(slot);
+                }
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("Root");
+            ui.add(egui::DragValue::new(&mut state.root).range(12..=96));
+            ui.label("Steps");
+            let current = state.current;
+            let pattern = &mut state.patterns[current];
+            ui.selectable_value(&mut pattern.length, 16, "16");
+            ui.selectable_value(&mut pattern.length, 32, "32");
+        });
+        ui.separator();
+
+        let position = state.position;
+        let current = state.current;
+        let pattern = &mut state.patterns[current];
+        egui::Grid::new("Steps").show(ui, |ui| {
+            for (index, step) in pattern.steps[..pattern.length].iter_mut().enumerate() {
+                draw_step(step, index, position == Some(index), ui);
+                if index % 8 == 7 {
+                    ui.end_row();
+                }
+            }
+        });
+    });
+}
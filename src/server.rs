@@ -1,24 +1,59 @@
 use std::mem::size_of;
 use crate::app::Message;
-use crate::bindings::{ParameterType_Waveform, ParameterValue, Section_Osc1, SynthMessage, Patch};
+use crate::bindings::{SynthMessage, Patch};
+use crate::synth::SynthCommand;
+
+use crate::midi_file::NoteEvent;
+use crate::recorder::Recorder;
 
 use std::sync::mpsc::{Sender, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use zmq;
 
-const ADDRESS: &str = "tcp://bela.local:5555";
+/// Where the synth listens unless another endpoint is set.
+pub const DEFAULT_ADDRESS: &str = "tcp://bela.local:5555";
+
+/// How the link to the synth is doing, for the Settings menu.
+#[derive(Clone, PartialEq)]
+pub enum Connection {
+    /// Connecting to the endpoint, or the synth isn't taking messages, which are dropped until it
+    /// does.
+    Waiting,
+    /// The synth took the last message sent, or sent a patch.
+    Connected,
+    Failed(String),
+}
 
 
 
-pub fn run_server(rx: Receiver<Message>, tx: Sender<Patch>){
+/// Talks to the synth at `endpoint`, reconnecting whenever it is changed, and keeps `status` up
+/// to date. ZeroMQ connects in the background, so only messages getting through show that the
+/// synth is there.
+pub fn run_server(rx: Receiver<Message>, tx: Sender<Patch>, recorder: Arc<Mutex<Recorder>>, endpoint: Arc<Mutex<String>>, status: Arc<Mutex<Connection>>){
     let mut ctx = zmq::Context::new();
     let mut server = ctx.socket(zmq::PAIR).expect("Failed to create socket");
-    server.connect(ADDRESS).unwrap();
+    let mut address = String::new();
+    let mut connected = false;
 
     'outer: loop {
+        let wanted = endpoint.lock().unwrap().clone();
+        if wanted != address {
+            if connected {
+                let _ = server.disconnect(&address);
+            }
+            let state = match server.connect(&wanted) {
+                Ok(()) => Connection::Waiting,
+                Err(e) => Connection::Failed(format!("Failed to connect to {}: {}", wanted, e)),
+            };
+            connected = state == Connection::Waiting;
+            *status.lock().unwrap() = state;
+            address = wanted;
+        }
         match server.recv_bytes(zmq::DONTWAIT) {
             Ok(msg) => {
                 if msg.len() == size_of::<Patch>() {
+                    *status.lock().unwrap() = Connection::Connected;
                     let patch = unsafe { *(msg.as_ptr() as *const Patch)};
                     if let Err(_) = tx.send(patch){
                         break 'outer;
@@ -26,9 +61,9 @@ pub fn run_server(rx: Receiver<Message>, tx: Sender<Patch>){
                 }
             }
             Err(zmq::Error::EAGAIN) => {},
-            Err(e) => {//
-                eprintln!("{:?}", e);
-               }
+            Err(e) => {
+                *status.lock().unwrap() = Connection::Failed(format!("Failed to receive from {}: {}", address, e));
+            }
         }
         'rx_loop: loop {
             match rx.try_recv() {
@@ -36,10 +71,24 @@ pub fn run_server(rx: Receiver<Message>, tx: Sender<Patch>){
                     break 'outer;
                 }
                 Ok(msg) => {
+                    match msg {
+                        Message::NoteOn(note, vel, time) => recorder.lock().unwrap().record(NoteEvent::On(note, vel), time),
+                        Message::NoteOff(note, time) => recorder.lock().unwrap().record(NoteEvent::Off(note), time),
+                        _ => {}
+                    }
+                    if !connected {
+                        continue 'rx_loop;
+                    }
                     let to_osc = SynthMessage::from(msg);
                     let bytes = unsafe{ any_as_u8_slice(&to_osc) };
 
-                    server.send(bytes, zmq::DONTWAIT).expect("Failed to send to server");
+                    let state = match server.send(bytes, zmq::DONTWAIT) {
+                        Ok(()) => Connection::Connected,
+                        // Nothing has connected at the other end yet.
+                        Err(zmq::Error::EAGAIN) => Connection::Waiting,
+                        Err(e) => Connection::Failed(format!("Failed to send to {}: {}", address, e)),
+                    };
+                    *status.lock().unwrap() = state;
                 },
                 _ => { break 'rx_loop; }
             }
@@ -58,21 +107,11 @@ unsafe fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8] {
 
 impl From<Message> for SynthMessage {
     fn from(value: Message) -> Self {
-        match value {
-            Message::SetWaveform(osc, waveform) => {
-                SynthMessage {
-                    channel: 16,
-                    destination: Section_Osc1,
-                    parameter: ParameterType_Waveform,
-                    value:  ParameterValue{ value_WaveformEnum: waveform }
-                }
-            },
-            Message::SetParameter(section, param_type, value) =>{
-                SynthMessage{channel:16, destination:section, parameter: param_type, value}
-            }
-            _ => {
-                SynthMessage { channel: 16, destination: 0, parameter: 0, value: ParameterValue { value_int8_t: 0 } }
-            }
-        }
+        let command = match value {
+            Message::Synth(command) => command,
+            Message::NoteOn(note, vel, _) => SynthCommand::NoteOn(note, vel),
+            Message::NoteOff(note, _) => SynthCommand::NoteOff(note),
+        };
+        command.into()
     }
-}
\ No newline at end of file
+}
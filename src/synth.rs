@@ -0,0 +1,333 @@
+use crate::app::Note;
+use crate::bindings::{FilterModeEnum_HP, FilterModeEnum_LP, NoteOnOffEvent, ParameterType_Attack, ParameterType_Coarse, ParameterType_Cutoff, ParameterType_Decay, ParameterType_Emphasis, ParameterType_Fine, ParameterType_Frequency, ParameterType_Gain, ParameterType_Mix, ParameterType_Mode, ParameterType_NoteEvents, ParameterType_Release, ParameterType_Resonance, ParameterType_Sustain, ParameterType_Waveform, ParameterValue, Section_Amp, Section_Filter, Section_Global, Section_Osc1, Section_Osc2, Section_Osc3, SynthMessage, WaveformEnum_SAW, WaveformEnum_SIN, WaveformEnum_SQR};
+
+/// Channel the synth listens on for parameter changes and notes.
+const CHANNEL: u8 = 16;
+
+/// Declares a Rust enum over a set of bindgen constants, convertible to and from the raw `u8`.
+macro_rules! wire_enum {
+    ($(#[$meta:meta])* $name:ident { $($variant:ident = $value:path),* $(,)? }) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum $name {
+            $($variant),*
+        }
+
+        impl $name {
+            pub const ALL: &'static [$name] = &[$($name::$variant),*];
+        }
+
+        impl TryFrom<u8> for $name {
+            type Error = String;
+
+            fn try_from(value: u8) -> Result<Self, Self::Error> {
+                match value {
+                    $($value => Ok($name::$variant),)*
+                    _ => Err(format!(concat!("Invalid ", stringify!($name), " {}"), value)),
+                }
+            }
+        }
+
+        impl From<$name> for u8 {
+            fn from(value: $name) -> Self {
+                match value {
+                    $($name::$variant => $value),*
+                }
+            }
+        }
+    };
+}
+
+wire_enum!(Waveform {
+    Saw = WaveformEnum_SAW,
+    Square = WaveformEnum_SQR,
+    Sine = WaveformEnum_SIN,
+});
+
+wire_enum!(FilterMode {
+    HighPass = FilterModeEnum_HP,
+    LowPass = FilterModeEnum_LP,
+});
+
+wire_enum!(SynthSection {
+    Global = Section_Global,
+    Osc1 = Section_Osc1,
+    Osc2 = Section_Osc2,
+    Osc3 = Section_Osc3,
+    Filter = Section_Filter,
+    Amp = Section_Amp,
+});
+
+wire_enum!(SynthParameter {
+    Waveform = ParameterType_Waveform,
+    Coarse = ParameterType_Coarse,
+    Mode = ParameterType_Mode,
+    Cutoff = ParameterType_Cutoff,
+    Fine = ParameterType_Fine,
+    Frequency = ParameterType_Frequency,
+    Resonance = ParameterType_Resonance,
+    Emphasis = ParameterType_Emphasis,
+    Mix = ParameterType_Mix,
+    Gain = ParameterType_Gain,
+    NoteEvents = ParameterType_NoteEvents,
+    Attack = ParameterType_Attack,
+    Decay = ParameterType_Decay,
+    Sustain = ParameterType_Sustain,
+    Release = ParameterType_Release,
+});
+
+wire_enum!(Oscillator {
+    Osc1 = Section_Osc1,
+    Osc2 = Section_Osc2,
+    Osc3 = Section_Osc3,
+});
+
+wire_enum!(
+    /// The sections with an envelope.
+    EnvelopeSection {
+        Filter = Section_Filter,
+        Amp = Section_Amp,
+    }
+);
+
+wire_enum!(Stage {
+    Attack = ParameterType_Attack,
+    Decay = ParameterType_Decay,
+    Sustain = ParameterType_Sustain,
+    Release = ParameterType_Release,
+});
+
+/// A parameter value tagged with its type, the safe counterpart of `ParameterValue`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParamValue {
+    Float(f32),
+    Int8(i8),
+    Uint8(u8),
+    Waveform(Waveform),
+    FilterMode(FilterMode),
+    Note { note: Note, vel: u8 },
+}
+
+impl From<ParamValue> for ParameterValue {
+    fn from(value: ParamValue) -> Self {
+        match value {
+            ParamValue::Float(v) => ParameterValue { value_float: v },
+            ParamValue::Int8(v) => ParameterValue { value_int8_t: v },
+            ParamValue::Uint8(v) => ParameterValue { value_uint8_t: v },
+            ParamValue::Waveform(v) => ParameterValue { value_WaveformEnum: v.into() },
+            ParamValue::FilterMode(v) => ParameterValue { value_FilterModeEnum: v.into() },
+            ParamValue::Note { note, vel } => ParameterValue { noteEvent: NoteOnOffEvent { note, vel } },
+        }
+    }
+}
+
+/// Everything that can be sent to the synth. Each variant carries exactly the value type its
+/// parameter takes, so a command can't address a parameter that doesn't exist or carry the
+/// wrong kind of value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SynthCommand {
+    /// Frequency of A4, in Hz.
+    Tuning(f32),
+    Mix(f32),
+    Waveform(Oscillator, Waveform),
+    Coarse(Oscillator, i8),
+    Fine(Oscillator, i8),
+    OscGain(Oscillator, i8),
+    FilterMode(FilterMode),
+    Cutoff(f32),
+    Resonance(u8),
+    Emphasis(f32),
+    AmpGain(i8),
+    Envelope(EnvelopeSection, Stage, f32),
+    NoteOn(Note, u8),
+    NoteOff(Note),
+}
+
+impl SynthCommand {
+    /// Builds the command for a parameter and value, if the synth has that parameter and it
+    /// takes that type of value.
+    pub fn new(section: SynthSection, parameter: SynthParameter, value: ParamValue) -> Result<Self, String> {
+        use SynthParameter as P;
+        let invalid = || format!("{:?} {:?} can't be set to {:?}", section, parameter, value);
+        if let Ok(osc) = Oscillator::try_from(u8::from(section)) {
+            return match (parameter, value) {
+                (P::Waveform, ParamValue::Waveform(v)) => Ok(SynthCommand::Waveform(osc, v)),
+                (P::Coarse, ParamValue::Int8(v)) => Ok(SynthCommand::Coarse(osc, v)),
+                (P::Fine, ParamValue::Int8(v)) => Ok(SynthCommand::Fine(osc, v)),
+                (P::Gain, ParamValue::Int8(v)) => Ok(SynthCommand::OscGain(osc, v)),
+                _ => Err(invalid()),
+            };
+        }
+        let envelope = EnvelopeSection::try_from(u8::from(section));
+        let stage = Stage::try_from(u8::from(parameter));
+        if let (Ok(envelope), Ok(stage), ParamValue::Float(v)) = (envelope, stage, value) {
+            return Ok(SynthCommand::Envelope(envelope, stage, v));
+        }
+        Ok(match (section, parameter, value) {
+            (SynthSection::Global, P::Frequency, ParamValue::Float(v)) => SynthCommand::Tuning(v),
+            (SynthSection::Global, P::Mix, ParamValue::Float(v)) => SynthCommand::Mix(v),
+            (SynthSection::Global, P::NoteEvents, ParamValue::Note { note, vel: 0 }) => SynthCommand::NoteOff(note),
+            (SynthSection::Global, P::NoteEvents, ParamValue::Note { note, vel }) => SynthCommand::NoteOn(note, vel),
+            (SynthSection::Filter, P::Mode, ParamValue::FilterMode(v)) => SynthCommand::FilterMode(v),
+            (SynthSection::Filter, P::Cutoff, ParamValue::Float(v)) => SynthCommand::Cutoff(v),
+            (SynthSection::Filter, P::Resonance, ParamValue::Uint8(v)) => SynthCommand::Resonance(v),
+            (SynthSection::Filter, P::Emphasis, ParamValue::Float(v)) => SynthCommand::Emphasis(v),
+            (SynthSection::Amp, P::Gain, ParamValue::Int8(v)) => SynthCommand::AmpGain(v),
+            _ => return Err(invalid()),
+        })
+    }
+
+    pub fn section(&self) -> SynthSection {
+        let section = match *self {
+            SynthCommand::Tuning(_) | SynthCommand::Mix(_) | SynthCommand::NoteOn(..) | SynthCommand::NoteOff(_) => Section_Global,
+            SynthCommand::Waveform(osc, _) | SynthCommand::Coarse(osc, _) | SynthCommand::Fine(osc, _) | SynthCommand::OscGain(osc, _) => osc.into(),
+            SynthCommand::FilterMode(_) | SynthCommand::Cutoff(_) | SynthCommand::Resonance(_) | SynthCommand::Emphasis(_) => Section_Filter,
+            SynthCommand::AmpGain(_) => Section_Amp,
+            SynthCommand::Envelope(envelope, _, _) => envelope.into(),
+        };
+        SynthSection::try_from(section).expect("every command addresses a section")
+    }
+
+    pub fn parameter(&self) -> SynthParameter {
+        match *self {
+            SynthCommand::Tuning(_) => SynthParameter::Frequency,
+            SynthCommand::Mix(_) => SynthParameter::Mix,
+            SynthCommand::Waveform(..) => SynthParameter::Waveform,
+            SynthCommand::Coarse(..) => SynthParameter::Coarse,
+            SynthCommand::Fine(..) => SynthParameter::Fine,
+            SynthCommand::OscGain(..) | SynthCommand::AmpGain(_) => SynthParameter::Gain,
+            SynthCommand::FilterMode(_) => SynthParameter::Mode,
+            SynthCommand::Cutoff(_) => SynthParameter::Cutoff,
+            SynthCommand::Resonance(_) => SynthParameter::Resonance,
+            SynthCommand::Emphasis(_) => SynthParameter::Emphasis,
+            SynthCommand::Envelope(_, stage, _) => {
+                SynthParameter::try_from(u8::from(stage)).expect("every stage is a parameter")
+            }
+            SynthCommand::NoteOn(..) | SynthCommand::NoteOff(_) => SynthParameter::NoteEvents,
+        }
+    }
+
+    pub fn value(&self) -> ParamValue {
+        match *self {
+            SynthCommand::Tuning(v)
+            | SynthCommand::Mix(v)
+            | SynthCommand::Cutoff(v)
+            | SynthCommand::Emphasis(v)
+            | SynthCommand::Envelope(_, _, v) => ParamValue::Float(v),
+            SynthCommand::Coarse(_, v) | SynthCommand::Fine(_, v) | SynthCommand::OscGain(_, v) | SynthCommand::AmpGain(v) => ParamValue::Int8(v),
+            SynthCommand::Resonance(v) => ParamValue::Uint8(v),
+            SynthCommand::Waveform(_, v) => ParamValue::Waveform(v),
+            SynthCommand::FilterMode(v) => ParamValue::FilterMode(v),
+            SynthCommand::NoteOn(note, vel) => ParamValue::Note { note, vel },
+            SynthCommand::NoteOff(note) => ParamValue::Note { note, vel: 0 },
+        }
+    }
+}
+
+impl From<SynthCommand> for SynthMessage {
+    fn from(command: SynthCommand) -> Self {
+        SynthMessage {
+            destination: command.section().into(),
+            parameter: command.parameter().into(),
+            channel: CHANNEL,
+            value: command.value().into(),
+        }
+    }
+}
+
+impl TryFrom<SynthMessage> for SynthCommand {
+    type Error = String;
+
+    fn try_from(message: SynthMessage) -> Result<Self, Self::Error> {
+        use SynthParameter as P;
+        let section = SynthSection::try_from(message.destination)?;
+        let parameter = SynthParameter::try_from(message.parameter)?;
+        let raw = message.value;
+        // SAFETY: every field of the union is plain data; the parameter says which one is meant,
+        // and enum values are checked by `try_from`.
+        let value = unsafe {
+            match (section, parameter) {
+                (_, P::NoteEvents) => ParamValue::Note { note: raw.noteEvent.note, vel: raw.noteEvent.vel },
+                (_, P::Waveform) => ParamValue::Waveform(Waveform::try_from(raw.value_WaveformEnum)?),
+                (_, P::Mode) => ParamValue::FilterMode(FilterMode::try_from(raw.value_FilterModeEnum)?),
+                (_, P::Coarse | P::Fine | P::Gain) => ParamValue::Int8(raw.value_int8_t),
+                (_, P::Resonance) => ParamValue::Uint8(raw.value_uint8_t),
+                _ => ParamValue::Float(raw.value_float),
+            }
+        };
+        SynthCommand::new(section, parameter, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every kind of command, with every enum value and the ends of each value's range.
+    fn commands() -> Vec<SynthCommand> {
+        let mut commands = vec![
+            SynthCommand::Tuning(415.0),
+            SynthCommand::Tuning(466.0),
+            SynthCommand::Mix(0.0),
+            SynthCommand::Mix(1.0),
+            SynthCommand::Cutoff(20.0),
+            SynthCommand::Cutoff(20000.0),
+            SynthCommand::Resonance(0),
+            SynthCommand::Resonance(u8::MAX),
+            SynthCommand::Emphasis(0.0),
+            SynthCommand::Emphasis(1.0),
+            SynthCommand::AmpGain(i8::MIN),
+            SynthCommand::AmpGain(i8::MAX),
+            SynthCommand::NoteOn(0, 1),
+            SynthCommand::NoteOn(127, 127),
+            SynthCommand::NoteOff(0),
+            SynthCommand::NoteOff(127),
+        ];
+        commands.extend(FilterMode::ALL.iter().map(|mode| SynthCommand::FilterMode(*mode)));
+        for osc in Oscillator::ALL {
+            commands.extend(Waveform::ALL.iter().map(|waveform| SynthCommand::Waveform(*osc, *waveform)));
+            for value in [i8::MIN, -1, 0, i8::MAX] {
+                commands.extend([SynthCommand::Coarse(*osc, value), SynthCommand::Fine(*osc, value), SynthCommand::OscGain(*osc, value)]);
+            }
+        }
+        for envelope in EnvelopeSection::ALL {
+            for stage in Stage::ALL {
+                commands.extend([20.0, 0.5, 2000.0].map(|value| SynthCommand::Envelope(*envelope, *stage, value)));
+            }
+        }
+        commands
+    }
+
+    #[test]
+    fn commands_round_trip_through_messages() {
+        for command in commands() {
+            let message = SynthMessage::from(command);
+            assert_eq!(message.destination, u8::from(command.section()));
+            assert_eq!(message.parameter, u8::from(command.parameter()));
+            assert_eq!(message.channel, CHANNEL);
+            assert_eq!(SynthCommand::try_from(message), Ok(command));
+        }
+    }
+
+    #[test]
+    fn note_on_without_velocity_is_note_off() {
+        let message = SynthMessage::from(SynthCommand::NoteOn(60, 0));
+        assert_eq!(SynthCommand::try_from(message), Ok(SynthCommand::NoteOff(60)));
+    }
+
+    #[test]
+    fn invalid_messages_are_rejected() {
+        let mut message = SynthMessage::from(SynthCommand::Waveform(Oscillator::Osc1, Waveform::Saw));
+        message.value.value_WaveformEnum = 9;
+        assert!(SynthCommand::try_from(message).is_err());
+
+        let mut message = SynthMessage::from(SynthCommand::Cutoff(440.0));
+        message.destination = 9;
+        assert!(SynthCommand::try_from(message).is_err());
+        message.destination = Section_Osc1;
+        assert!(SynthCommand::try_from(message).is_err());
+        message.destination = Section_Filter;
+        message.parameter = 99;
+        assert!(SynthCommand::try_from(message).is_err());
+    }
+}
@@ -0,0 +1,196 @@
+use crate::binary::{decode_patch, encode_patch, PATCH_RECORD_SIZE, PROTOCOL_VERSION};
+use crate::bindings::Patch;
+
+const SYSEX_START: u8 = 0xF0;
+const SYSEX_END: u8 = 0xF7;
+/// The ID set aside for non-commercial use.
+const MANUFACTURER_ID: u8 = 0x7D;
+/// Device ID written into dumps. Dumps for this ID or for all devices are accepted.
+pub const DEVICE_ID: u8 = 0x00;
+const ALL_DEVICES: u8 = 0x7F;
+const PATCH_DUMP: u8 = 0x01;
+/// Slot number for the patch being edited rather than one in the bank.
+const EDIT_BUFFER: u8 = 0x7F;
+/// A patch record packed into 7-bit bytes: each 7 bytes become 8.
+const PACKED_SIZE: usize = PATCH_RECORD_SIZE.div_ceil(7) * 8;
+/// Start, manufacturer, device, command, slot, version, data, checksum and end.
+const DUMP_SIZE: usize = 6 + PACKED_SIZE + 2;
+/// Extension of SysEx files, as used by librarians.
+pub const SYSEX_EXTENSION: &str = "syx";
+
+/// Where a dumped patch belongs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DumpSlot {
+    EditBuffer,
+    Bank(usize),
+}
+
+pub fn is_sysex_path(path: &std::path::Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case(SYSEX_EXTENSION))
+}
+
+/// Packs 8-bit data for SysEx: each group of up to 7 bytes is preceded by a byte holding their
+/// top bits, the first byte's in bit 0.
+fn pack_7bit(data: &[u8]) -> Vec<u8> {
+    let mut packed = Vec::with_capacity(data.len().div_ceil(7) * 8);
+    for group in data.chunks(7) {
+        let high_bits = group.iter().enumerate().fold(0, |bits, (index, byte)| bits | ((byte >> 7) << index));
+        packed.push(high_bits);
+        packed.extend(group.iter().map(|byte| byte & 0x7F));
+    }
+    packed
+}
+
+fn unpack_7bit(packed: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(packed.len() / 8 * 7);
+    for group in packed.chunks(8) {
+        let (high_bits, bytes) = group.split_first().unwrap();
+        data.extend(bytes.iter().enumerate().map(|(index, byte)| byte | (((high_bits >> index) & 1) << 7)));
+    }
+    data
+}
+
+/// Roland-style checksum: the packed data plus the checksum sum to zero in the low 7 bits.
+fn checksum(packed: &[u8]) -> u8 {
+    let sum = packed.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    sum.wrapping_neg() & 0x7F
+}
+
+/// A single patch dump:
+///
+/// ```text
+/// F0 7D <device> 01 <slot> <version> <64 bytes packed record> <checksum> F7
+/// ```
+///
+/// where the slot is the bank slot, or 7F for the patch being edited. Only slots below 7F fit.
+pub fn encode_dump(slot: DumpSlot, patch: &Patch) -> Result<Vec<u8>, String> {
+    let slot = match slot {
+        DumpSlot::EditBuffer => EDIT_BUFFER,
+        DumpSlot::Bank(index) if index < EDIT_BUFFER as usize => index as u8,
+        DumpSlot::Bank(index) => return Err(format!("Slot {} can't be dumped: SysEx has room for {} slots", index + 1, EDIT_BUFFER)),
+    };
+    let packed = pack_7bit(&encode_patch(patch));
+    let mut bytes = vec![SYSEX_START, MANUFACTURER_ID, DEVICE_ID, PATCH_DUMP, slot, PROTOCOL_VERSION as u8 & 0x7F];
+    bytes.extend_from_slice(&packed);
+    bytes.push(checksum(&packed));
+    bytes.push(SYSEX_END);
+    Ok(bytes)
+}
+
+pub fn is_sysex(bytes: &[u8]) -> bool {
+    bytes.first() == Some(&SYSEX_START)
+}
+
+/// Whether a message is a dump meant for us, as opposed to SysEx for other devices.
+pub fn is_dump(bytes: &[u8]) -> bool {
+    matches!(bytes, [SYSEX_START, MANUFACTURER_ID, device, PATCH_DUMP, ..] if *device == DEVICE_ID || *device == ALL_DEVICES)
+}
+
+pub fn decode_dump(bytes: &[u8]) -> Result<(DumpSlot, Patch), String> {
+    if !is_dump(bytes) {
+        return Err("Not a patch dump".to_string());
+    }
+    if bytes.len() != DUMP_SIZE || bytes[DUMP_SIZE - 1] != SYSEX_END {
+        return Err(format!("A patch dump is {} bytes, not {}", DUMP_SIZE, bytes.len()));
+    }
+    let version = bytes[5] as u16;
+    if version > PROTOCOL_VERSION {
+        return Err(format!("Protocol version {} is newer than this app supports ({})", version, PROTOCOL_VERSION));
+    }
+    let packed = &bytes[6..6 + PACKED_SIZE];
+    if checksum(packed) != bytes[6 + PACKED_SIZE] {
+        return Err("The checksum doesn't match: the dump is damaged".to_string());
+    }
+    let slot = match bytes[4] {
+        EDIT_BUFFER => DumpSlot::EditBuffer,
+        index => DumpSlot::Bank(index as usize),
+    };
+    let record = unpack_7bit(packed);
+    Ok((slot, decode_patch(&record[..PATCH_RECORD_SIZE])?))
+}
+
+/// Reads every dump in a `.syx` file, skipping SysEx for other devices.
+pub fn decode_dumps(bytes: &[u8]) -> Result<Vec<(DumpSlot, Patch)>, String> {
+    let mut dumps = Vec::new();
+    let mut rest = bytes;
+    while let Some(start) = rest.iter().position(|byte| *byte == SYSEX_START) {
+        let end = rest[start..].iter().position(|byte| *byte == SYSEX_END)
+            .ok_or("The file ends in the middle of a SysEx message")?;
+        let message = &rest[start..=start + end];
+        if is_dump(message) {
+            dumps.push(decode_dump(message).map_err(|e| format!("Dump {}: {}", dumps.len() + 1, e))?);
+        }
+        rest = &rest[start + end + 1..];
+    }
+    if dumps.is_empty() {
+        return Err("The file has no patch dumps".to_string());
+    }
+    Ok(dumps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::PatchUI;
+
+    fn patch() -> Patch {
+        Patch { Osc1_Coarse: -12, Osc3_Gain: i8::MIN, Filter_Resonance: 255, Filter_Cutoff: 1234.5, ..Patch::from(&PatchUI::default()) }
+    }
+
+    #[test]
+    fn packing_round_trips() {
+        let data: Vec<u8> = (0..=255).collect();
+        for len in 0..=data.len() {
+            let packed = pack_7bit(&data[..len]);
+            assert_eq!(packed.len(), len + len.div_ceil(7));
+            assert!(packed.iter().all(|byte| *byte < 0x80), "packed data is 7-bit");
+            assert_eq!(unpack_7bit(&packed), &data[..len]);
+        }
+        assert_eq!(pack_7bit(&[0xFF, 0x01]), [0b01, 0x7F, 0x01]);
+    }
+
+    #[test]
+    fn dumps_round_trip() {
+        for slot in [DumpSlot::EditBuffer, DumpSlot::Bank(0), DumpSlot::Bank(31), DumpSlot::Bank(EDIT_BUFFER as usize - 1)] {
+            let dump = encode_dump(slot, &patch()).unwrap();
+            assert_eq!(dump.len(), DUMP_SIZE);
+            assert!(is_sysex(&dump) && is_dump(&dump));
+            assert!(dump[1..DUMP_SIZE - 1].iter().all(|byte| *byte < 0x80), "only the start and end have the top bit set");
+            let (decoded_slot, decoded) = decode_dump(&dump).unwrap();
+            assert_eq!(decoded_slot, slot);
+            assert_eq!(encode_patch(&decoded), encode_patch(&patch()));
+        }
+    }
+
+    #[test]
+    fn slots_that_dont_fit_are_refused() {
+        assert!(encode_dump(DumpSlot::Bank(EDIT_BUFFER as usize), &patch()).is_err());
+        assert!(encode_dump(DumpSlot::Bank(200), &patch()).is_err());
+    }
+
+    #[test]
+    fn damaged_dumps_are_rejected() {
+        let dump = encode_dump(DumpSlot::Bank(3), &patch()).unwrap();
+        let mut damaged = dump.clone();
+        damaged[10] ^= 1;
+        assert!(decode_dump(&damaged).is_err());
+        assert!(decode_dump(&dump[..DUMP_SIZE - 1]).is_err());
+        let mut newer = dump.clone();
+        newer[5] = PROTOCOL_VERSION as u8 + 1;
+        assert!(decode_dump(&newer).is_err());
+    }
+
+    #[test]
+    fn files_of_dumps_skip_other_sysex() {
+        let other = [SYSEX_START, 0x41, 0x10, 0x42, 0x12, SYSEX_END];
+        let mut bytes = other.to_vec();
+        for index in 0..3 {
+            bytes.extend(encode_dump(DumpSlot::Bank(index), &patch()).unwrap());
+            bytes.extend(other);
+        }
+        let dumps = decode_dumps(&bytes).unwrap();
+        assert_eq!(dumps.iter().map(|(slot, _)| *slot).collect::<Vec<_>>(), [0, 1, 2].map(DumpSlot::Bank));
+        assert!(decode_dumps(&other).is_err());
+        assert!(decode_dumps(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
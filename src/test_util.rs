@@ -0,0 +1,26 @@
+use crate::app::PatchUI;
+use crate::bindings::{Patch, Section_Global};
+use crate::params::PARAMS;
+
+/// A patch with every parameter at a different point of its range, starting from `x`.
+pub fn sample_patch(x: f32) -> Patch {
+    let mut patch = PatchUI::default();
+    for (index, desc) in PARAMS.iter().enumerate().filter(|(_, d)| d.section != Section_Global) {
+        patch.set(desc.section, desc.parameter, desc.denormalise((x + index as f32 * 0.137) % 1.0));
+    }
+    Patch::from(&patch)
+}
+
+/// Patches with every parameter at its minimum, maximum, default and points between.
+pub fn sample_patches() -> Vec<Patch> {
+    let mut patches = vec![Patch::from(&PatchUI::default())];
+    for x in [0.0, 1.0] {
+        let mut patch = PatchUI::default();
+        for desc in PARAMS.iter().filter(|d| d.section != Section_Global) {
+            patch.set(desc.section, desc.parameter, desc.denormalise(x));
+        }
+        patches.push(Patch::from(&patch));
+    }
+    patches.extend([0.1, 0.25, 0.5, 0.9].map(sample_patch));
+    patches
+}